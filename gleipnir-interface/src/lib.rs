@@ -7,6 +7,7 @@ use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::ops::RangeInclusive;
 
 use libc;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 pub mod unixtransport;
@@ -15,7 +16,10 @@ pub mod unixtransport;
 pub trait Daemon {
     async fn init_monitor(socket_path: String);
     async fn unlock() -> bool;
-    async fn set_rules(rules: Rules);
+    /// Fails if the rules can't be applied, like an `exe` pattern that doesn't compile
+    async fn set_rules(rules: Rules) -> Result<(), String>;
+    /// Answer a `Monitor::on_connection_prompt`, ignored if it has timed out
+    async fn answer_prompt(id: u64, answer: PromptAnswer);
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -23,6 +27,97 @@ pub struct Rules {
     pub default_target: RuleTarget,
     pub rules: Vec<Rule>,
     pub rate_rules: Vec<RateLimitRule>,
+    /// For packets whose owning process can't be found
+    #[serde(default)]
+    pub unknown_policy: UnknownPolicy,
+    /// Used by `UnknownPolicy::Chain`, the `exe` of these rules must be `None`
+    #[serde(default)]
+    pub unknown_rules: Vec<Rule>,
+    /// Seconds to wait for an answer to `RuleTarget::Ask`
+    #[serde(default = "default_ask_timeout")]
+    pub ask_timeout: u32,
+    /// Used when nobody answers in time, `Ask` is treated as `Drop`
+    #[serde(default = "default_ask_fallback")]
+    pub ask_fallback: RuleTarget,
+    /// File name patterns of programs that run scripts, see `Rule::script`
+    #[serde(default = "default_interpreters")]
+    pub interpreters: Vec<String>,
+    /// Applied instead of the target of a rule whose `sha256` doesn't match
+    #[serde(default = "default_hash_mismatch")]
+    pub hash_mismatch: RuleTarget,
+    /// For sockets shared by several processes
+    #[serde(default)]
+    pub owner_policy: OwnerPolicy,
+}
+
+fn default_ask_timeout() -> u32 {
+    30
+}
+
+fn default_ask_fallback() -> RuleTarget {
+    RuleTarget::Drop
+}
+
+fn default_hash_mismatch() -> RuleTarget {
+    RuleTarget::Drop
+}
+
+fn default_interpreters() -> Vec<String> {
+    [
+        "python*", "pypy*", "node", "nodejs", "deno", "java", "perl*", "ruby*", "php*", "lua*",
+        "bash", "sh", "dash", "zsh",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect()
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            default_target: RuleTarget::Accept,
+            rules: Default::default(),
+            rate_rules: Default::default(),
+            unknown_policy: Default::default(),
+            unknown_rules: Default::default(),
+            ask_timeout: default_ask_timeout(),
+            ask_fallback: default_ask_fallback(),
+            interpreters: default_interpreters(),
+            hash_mismatch: default_hash_mismatch(),
+            owner_policy: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum UnknownPolicy {
+    Accept,
+    Drop,
+    /// Match `Rules::unknown_rules`, fall back to `Rules::default_target`
+    Chain,
+}
+
+impl Default for UnknownPolicy {
+    fn default() -> Self {
+        UnknownPolicy::Accept
+    }
+}
+
+/// Which process the rules are checked against, when a socket is inherited or passed
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum OwnerPolicy {
+    /// The most recently started one, usually it was forked to exec something else
+    LatestExec,
+    /// Every owner must be allowed, the strictest target is applied
+    All,
+    /// The one the others descend from
+    Parent,
+}
+
+impl Default for OwnerPolicy {
+    fn default() -> Self {
+        OwnerPolicy::LatestExec
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -35,6 +130,69 @@ pub struct RateLimitRule {
 pub trait Monitor {
     async fn on_packages(logs: Vec<PackageReport>);
     async fn on_rules_updated(rules: Rules);
+    /// A connection matched `RuleTarget::Ask`, answer with `Daemon::answer_prompt`
+    async fn on_connection_prompt(prompt: ConnectionPrompt);
+    /// Sent once for each process
+    async fn on_exe_alert(alert: ExeAlert);
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ExeAlert {
+    /// The executable doesn't have the hash pinned by `Rule::sha256`
+    HashMismatch {
+        pid: usize,
+        exe: String,
+        /// Index to `Rules::rules`
+        rule: usize,
+        expected: String,
+        /// `None` if it can't be read
+        actual: Option<String>,
+    },
+    /// The executable has been replaced or removed since the process started, usually by
+    /// a package upgrade
+    Deleted { pid: usize, exe: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConnectionPrompt {
+    pub id: u64,
+    /// 0 if the connection is owned by the kernel
+    pub pid: usize,
+    pub exe: String,
+    pub device: Device,
+    pub protocol: Proto,
+    /// The remote address
+    pub addr: SocketAddr,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PromptAnswer {
+    pub accept: bool,
+    pub remember: Remember,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Remember {
+    /// Only this connection
+    Once,
+    /// Add a rule that is forgotten when the daemon restarts
+    Temporary,
+    /// Add a rule and save it
+    Forever,
+}
+
+/// A process that started another one, directly or not
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Ancestor {
+    pub pid: usize,
+    pub exe: String,
+}
+
+/// A process with a socket open
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct Owner {
+    pub pid: usize,
+    pub exe: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -42,10 +200,28 @@ pub struct PackageReport {
     pub device: Device,
     pub protocol: Proto,
     pub addr: SocketAddr,
+    pub local_addr: SocketAddr,
     pub len: usize,
     pub exe: String,
     pub dropped: bool,
+    /// Index to `Rules::unknown_rules` when `unknown_process` is true
     pub matched_rule: Option<usize>,
+    /// The owning process can't be found, `exe` is empty
+    pub unknown_process: bool,
+    /// Owner of the socket, `None` if unknown or owned by the kernel
+    pub uid: Option<u32>,
+    /// Like `flatpak:org.mozilla.firefox`, `None` if the process is not sandboxed
+    pub app_id: Option<String>,
+    /// The script run by an interpreter, see `Rules::interpreters`
+    pub script: Option<String>,
+    /// The `exe` of the matched rule is this one's, see `Rule::descendants`
+    pub matched_ancestor: Option<Ancestor>,
+    /// The network interface it came from, or goes out of
+    pub interface: Option<String>,
+    /// `SO_REUSEPORT` sockets of different programs, the kernel may give it to any of them
+    pub ambiguous: bool,
+    /// Every owner of those sockets when `ambiguous`, the rules are checked against all of them
+    pub owners: Vec<Owner>,
 }
 
 #[derive(Debug, Eq, PartialEq, Hash, Copy, Clone, Ord, PartialOrd, Serialize, Deserialize)]
@@ -69,6 +245,17 @@ pub enum Proto {
     Tcp = libc::IPPROTO_TCP as isize,
     Udp = libc::IPPROTO_UDP as isize,
     UdpLite = libc::IPPROTO_UDPLITE as isize,
+    Icmp = libc::IPPROTO_ICMP as isize,
+    Icmpv6 = libc::IPPROTO_ICMPV6 as isize,
+}
+
+impl Proto {
+    pub fn is_icmp(&self) -> bool {
+        match self {
+            Proto::Icmp | Proto::Icmpv6 => true,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Proto {
@@ -77,6 +264,8 @@ impl fmt::Display for Proto {
             Proto::Tcp => "TCP",
             Proto::Udp => "UDP",
             Proto::UdpLite => "UDPLite",
+            Proto::Icmp => "ICMP",
+            Proto::Icmpv6 => "ICMPv6",
         };
         f.write_str(s)
     }
@@ -86,45 +275,478 @@ impl fmt::Display for Proto {
 pub enum RuleTarget {
     Accept,
     Drop,
+    /// Answer with a TCP RST, or an ICMP port-unreachable
+    Reject,
+    /// Hold the connection and ask the connected clients
+    Ask,
     RateLimit(usize), // index to rate_rules item
 }
 
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+impl Default for RuleTarget {
+    fn default() -> Self {
+        RuleTarget::Accept
+    }
+}
+
+/// What rules are matched against
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct PacketInfo<'a> {
+    pub device: Device,
+    pub protocol: Proto,
+    /// The remote address, the port is 0 for ICMP
+    pub addr: SocketAddr,
+    /// The address of this host, the port is the echo identifier for ICMP, or 0
+    pub local_addr: SocketAddr,
+    /// ICMP type and code
+    pub icmp: Option<(u8, u8)>,
+    /// The input interface, or the output one, `None` if it's gone already
+    pub interface: Option<&'a str>,
+    pub exe: &'a str,
+    /// Owner of the socket
+    pub uid: Option<u32>,
+    /// Effective group of the process
+    pub gid: Option<u32>,
+    /// The cgroup of the process, empty if unknown
+    pub cgroup: &'a str,
+    /// The sandboxed application or container the process belongs to
+    pub app_id: Option<&'a str>,
+    /// The arguments after `argv[0]`, empty unless `exe` is an interpreter
+    pub args: &'a [String],
+    /// Parent first, empty unless some rules match descendants
+    pub ancestors: &'a [Ancestor],
+    /// Hex SHA-256 of `exe`, `None` unless some rules pin it, or if it can't be read
+    pub exe_hash: Option<&'a str>,
+    /// Host names of `addr` seen in DNS responses to accepted queries
+    pub names: &'a [String],
+}
+
+/// How `Rule::exe` is matched
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ExeMatch {
+    Exact,
+    /// `*` matches any part of the path, including `/`
+    Glob,
+    /// Must match the whole path
+    Regex,
+}
+
+impl Default for ExeMatch {
+    fn default() -> Self {
+        ExeMatch::Exact
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Rule {
     pub device: Option<Device>,
     pub proto: Option<Proto>,
+    /// `[kernel]`, or `[kernel:<subsystem>]` like `[kernel:nfs]`, for the kernel itself
     pub exe: Option<String>,
+    #[serde(default)]
+    pub exe_match: ExeMatch,
     #[serde(with = "rangeinclusive_serde")]
     pub port: Option<RangeInclusive<u16>>,
     pub subnet: Option<(IpAddr, u8)>, // mask
+    #[serde(default, with = "rangeinclusive_serde")]
+    pub local_port: Option<RangeInclusive<u16>>,
+    #[serde(default)]
+    pub local_subnet: Option<(IpAddr, u8)>, // mask
+    /// Only matches ICMP packets when set
+    #[serde(default)]
+    pub icmp_type: Option<u8>,
+    #[serde(default)]
+    pub icmp_code: Option<u8>,
+    /// Like `*.example.com`, see `match_domain`
+    #[serde(default)]
+    pub domain: Option<String>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// See `match_cgroup`
+    #[serde(default)]
+    pub cgroup: Option<String>,
+    /// Like `flatpak:org.mozilla.firefox` or `docker:*`
+    #[serde(default)]
+    pub app_id: Option<String>,
+    /// Like `*/backup.py` or `http.server`, see `script_arg`
+    #[serde(default)]
+    pub script: Option<String>,
+    /// `exe` also matches the processes started by it
+    #[serde(default)]
+    pub descendants: bool,
+    /// Hex, descendants are not checked, see `Rules::hash_mismatch`
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// Like `wlan0` or `wg*`
+    #[serde(default)]
+    pub interface: Option<String>,
     pub target: RuleTarget,
+    /// Added by `Remember::Temporary`, not saved
+    #[serde(default)]
+    pub temporary: bool,
 }
 
 impl Rule {
-    pub fn match_target(
+    pub fn match_target(&self, packet: &PacketInfo) -> Option<RuleTarget> {
+        self.match_target_with(packet, |exe| self.match_exe(exe))
+    }
+
+    /// `match_exe` tells whether `exe` matches a path, so its pattern is compiled only once
+    pub fn match_target_with(
         &self,
-        device: Device,
-        protocol: Proto,
-        addr: SocketAddr,
-        exe: &str,
+        packet: &PacketInfo,
+        match_exe: impl Fn(&str) -> bool,
     ) -> Option<RuleTarget> {
+        let PacketInfo {
+            device,
+            protocol,
+            addr,
+            local_addr,
+            icmp,
+            interface,
+            exe,
+            uid,
+            gid,
+            cgroup,
+            app_id,
+            args,
+            ancestors,
+            exe_hash: _,
+            names,
+        } = *packet;
         if (self.device.is_none() || device == self.device.unwrap())
             && (self.proto.is_none() || protocol == self.proto.unwrap())
-            && (self.exe.is_none() || exe == self.exe.as_ref().unwrap())
+            && (self.interface.is_none()
+                || interface.map_or(false, |name| {
+                    match_glob(self.interface.as_ref().unwrap(), name, false)
+                }))
+            && (self.exe.is_none()
+                || match_exe(exe)
+                || self.descendant_of(ancestors, &match_exe).is_some())
+            && (self.uid.is_none() || uid == self.uid)
+            && (self.gid.is_none() || gid == self.gid)
+            && (self.cgroup.is_none() || match_cgroup(self.cgroup.as_ref().unwrap(), cgroup))
+            && (self.app_id.is_none()
+                || app_id.map_or(false, |id| {
+                    match_glob(self.app_id.as_ref().unwrap(), id, false)
+                }))
+            && (self.script.is_none()
+                || script_arg(exe, args).map_or(false, |script| {
+                    match_glob(self.script.as_ref().unwrap(), script, false)
+                }))
             && (self.port.is_none() || self.port.as_ref().unwrap().contains(&addr.port()))
-            && (self.subnet.is_none()
-                || (addr.is_ipv4() == self.subnet.unwrap().0.is_ipv4()
-                    && (match (addr.ip(), self.subnet.unwrap()) {
-                        (IpAddr::V4(addr), (IpAddr::V4(subnet), mask)) => addr.mask(mask) == subnet,
-                        (IpAddr::V6(addr), (IpAddr::V6(subnet), mask)) => addr.mask(mask) == subnet,
-                        _ => unreachable!(),
-                    })))
+            && (self.local_port.is_none()
+                || self
+                    .local_port
+                    .as_ref()
+                    .unwrap()
+                    .contains(&local_addr.port()))
+            && (self.icmp_type.is_none() || icmp.map(|(t, _)| t) == self.icmp_type)
+            && (self.icmp_code.is_none() || icmp.map(|(_, c)| c) == self.icmp_code)
+            && (self.domain.is_none()
+                || names
+                    .iter()
+                    .any(|name| match_domain(self.domain.as_ref().unwrap(), name)))
+            && (self.subnet.is_none() || in_subnet(addr.ip(), self.subnet.unwrap()))
+            && (self.local_subnet.is_none()
+                || in_subnet(local_addr.ip(), self.local_subnet.unwrap()))
         {
             Some(self.target)
         } else {
             None
         }
     }
+
+    /// The nearest ancestor `exe` matches, if it applies to descendants
+    pub fn descendant_of<'a>(
+        &self,
+        ancestors: &'a [Ancestor],
+        match_exe: impl Fn(&str) -> bool,
+    ) -> Option<&'a Ancestor> {
+        self.exe.as_ref().filter(|_| self.descendants)?;
+        ancestors.iter().find(|ancestor| match_exe(&ancestor.exe))
+    }
+
+    /// Compiles the pattern on every call, see `exe_regex`
+    pub fn match_exe(&self, exe: &str) -> bool {
+        match (&self.exe, self.exe_match) {
+            (None, _) => true,
+            (Some(pattern), ExeMatch::Exact) => exe == pattern,
+            (Some(pattern), ExeMatch::Glob) => match_glob(pattern, exe, false),
+            (Some(_), ExeMatch::Regex) => self
+                .exe_regex()
+                .unwrap()
+                .map_or(false, |re| re.is_match(exe)),
+        }
+    }
+
+    /// The pattern of `ExeMatch::Glob` and `ExeMatch::Regex` rules, anchored at both ends
+    pub fn exe_regex(&self) -> Option<Result<Regex, regex::Error>> {
+        let pattern = self.exe.as_ref()?;
+        let pattern = match self.exe_match {
+            ExeMatch::Exact => return None,
+            ExeMatch::Glob => {
+                let parts: Vec<_> = pattern.split('*').map(regex::escape).collect();
+                parts.join(".*")
+            }
+            ExeMatch::Regex => format!("(?:{})", pattern),
+        };
+        Some(Regex::new(&format!("^{}$", pattern)))
+    }
+}
+
+/// `*` matches any part of a name, including dots, letters are case insensitive
+pub fn match_domain(pattern: &str, name: &str) -> bool {
+    match_glob(pattern, name, true)
+}
+
+/// A pattern starting with `/` matches that cgroup and the ones below it, others match a
+/// unit anywhere in the path, like `app-firefox-*.scope`
+pub fn match_cgroup(pattern: &str, cgroup: &str) -> bool {
+    if cgroup.is_empty() {
+        return false;
+    }
+    if pattern.starts_with('/') {
+        let pattern = pattern.trim_end_matches('/');
+        cgroup.starts_with(pattern)
+            && (cgroup.len() == pattern.len() || cgroup.as_bytes()[pattern.len()] == b'/')
+    } else {
+        cgroup
+            .split('/')
+            .any(|unit| match_glob(pattern, unit, false))
+    }
+}
+
+/// Whether the file name of `exe` matches one of `patterns`, like `python3*`
+pub fn is_interpreter(patterns: &[String], exe: &str) -> bool {
+    let name = exe.rsplit('/').next().unwrap_or(exe);
+    !name.is_empty()
+        && patterns
+            .iter()
+            .any(|pattern| match_glob(pattern, name, false))
+}
+
+/// How the options of an interpreter are parsed, to tell which argument is the script
+struct Interpreter {
+    /// File name patterns
+    names: &'static [&'static str],
+    /// Followed by a value, like `-W ignore`
+    values: &'static [&'static str],
+    /// Followed by code to run, there is no script, like `-c 'import os'`
+    code: &'static [&'static str],
+    /// Followed by what is run instead of a file, like `-m http.server` or `-jar app.jar`
+    script: &'static [&'static str],
+    /// Come before the script, like `deno run`
+    commands: &'static [&'static str],
+    /// Single letter options can be combined, like `-uc`
+    clusters: bool,
+}
+
+const INTERPRETERS: &[Interpreter] = &[
+    Interpreter {
+        names: &["python*", "pypy*"],
+        values: &["-W", "-X", "--check-hash-based-pycs"],
+        code: &["-c"],
+        script: &["-m"],
+        commands: &[],
+        clusters: true,
+    },
+    Interpreter {
+        names: &["node", "nodejs"],
+        values: &[
+            "-r",
+            "--require",
+            "--import",
+            "--loader",
+            "--experimental-loader",
+            "--input-type",
+            "-C",
+            "--conditions",
+            "--title",
+        ],
+        code: &["-e", "--eval", "-p", "--print"],
+        script: &[],
+        commands: &[],
+        clusters: false,
+    },
+    Interpreter {
+        names: &["deno"],
+        values: &[
+            "-c",
+            "--config",
+            "--import-map",
+            "--location",
+            "--seed",
+            "--cert",
+            "--lock",
+            "-L",
+            "--log-level",
+        ],
+        code: &["eval"],
+        script: &[],
+        commands: &["run"],
+        clusters: false,
+    },
+    Interpreter {
+        names: &["java"],
+        values: &[
+            "-cp",
+            "-classpath",
+            "--class-path",
+            "-p",
+            "--module-path",
+            "--upgrade-module-path",
+            "--add-modules",
+            "--add-exports",
+            "--add-opens",
+            "--add-reads",
+            "--patch-module",
+            "--limit-modules",
+        ],
+        code: &[],
+        script: &["-jar", "-m", "--module"],
+        commands: &[],
+        clusters: false,
+    },
+    Interpreter {
+        names: &["perl*"],
+        values: &["-I"],
+        code: &["-e", "-E"],
+        script: &[],
+        commands: &[],
+        clusters: true,
+    },
+    Interpreter {
+        names: &["ruby*"],
+        values: &["-I", "-r", "-C", "-E", "--encoding"],
+        code: &["-e"],
+        script: &[],
+        commands: &[],
+        clusters: true,
+    },
+    Interpreter {
+        names: &["php*"],
+        values: &["-c", "-d", "-z"],
+        code: &["-r", "-B", "-R", "-E"],
+        script: &["-f"],
+        commands: &[],
+        clusters: true,
+    },
+    Interpreter {
+        names: &["lua*"],
+        values: &["-l"],
+        code: &["-e"],
+        script: &[],
+        commands: &[],
+        clusters: true,
+    },
+    Interpreter {
+        names: &["bash", "sh", "dash", "zsh"],
+        values: &["-o", "+o", "-O", "+O", "--rcfile", "--init-file"],
+        code: &["-c"],
+        script: &[],
+        commands: &[],
+        clusters: true,
+    },
+];
+
+/// Other interpreters from `Rules::interpreters`, all options are flags
+const UNKNOWN_INTERPRETER: Interpreter = Interpreter {
+    names: &[],
+    values: &[],
+    code: &[],
+    script: &[],
+    commands: &[],
+    clusters: false,
+};
+
+/// The script an interpreter runs, `None` if it runs code from its arguments or stdin
+pub fn script_arg<'a>(exe: &str, args: &'a [String]) -> Option<&'a str> {
+    let name = exe.rsplit('/').next().unwrap_or(exe);
+    let interpreter = INTERPRETERS
+        .iter()
+        .find(|i| {
+            i.names
+                .iter()
+                .any(|pattern| match_glob(pattern, name, false))
+        })
+        .unwrap_or(&UNKNOWN_INTERPRETER);
+    let mut args = args.iter().map(String::as_str);
+    while let Some(arg) = args.next() {
+        if interpreter.values.contains(&arg) {
+            args.next();
+        } else if interpreter.code.contains(&arg) {
+            return None;
+        } else if interpreter.script.contains(&arg) || arg == "--" {
+            return args.next();
+        } else if interpreter.commands.contains(&arg) || arg.starts_with("--") {
+            continue;
+        } else if arg == "-" {
+            // stdin
+            return None;
+        } else if !arg.starts_with('-') {
+            return Some(arg);
+        } else if interpreter.clusters {
+            // like `-uc`, `-Wignore` or `-mhttp.server`
+            for (i, c) in arg.char_indices().skip(1) {
+                let option = format!("-{}", c);
+                let rest = &arg[i + c.len_utf8()..];
+                if interpreter.values.contains(&option.as_str()) {
+                    if rest.is_empty() {
+                        args.next();
+                    }
+                    break;
+                } else if interpreter.code.contains(&option.as_str()) {
+                    return None;
+                } else if interpreter.script.contains(&option.as_str()) {
+                    return if rest.is_empty() {
+                        args.next()
+                    } else {
+                        Some(rest)
+                    };
+                }
+            }
+        }
+    }
+    None
+}
+
+fn in_subnet(ip: IpAddr, subnet: (IpAddr, u8)) -> bool {
+    match (ip, subnet) {
+        (IpAddr::V4(ip), (IpAddr::V4(subnet), mask)) => ip.mask(mask) == subnet,
+        (IpAddr::V6(ip), (IpAddr::V6(subnet), mask)) => ip.mask(mask) == subnet,
+        _ => false,
+    }
+}
+
+/// `*` matches any part of `name`
+fn match_glob(pattern: &str, name: &str, ignore_case: bool) -> bool {
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    let (mut p, mut n) = (0, 0);
+    // where to retry when the part after the last `*` doesn't match
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == name[n] || (ignore_case && c.eq_ignore_ascii_case(&name[n])) => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
 }
 
 mod rangeinclusive_serde {
@@ -198,7 +198,7 @@ Pane {
                     currentIndex: proto
                     onCurrentIndexChanged: if (proto != currentIndex) proto = currentIndex
                     width: defaultFont.width * 7 + indicator.width
-                    model: [qsTr("Any"), "TCP", "UDP", "UDPLite"]
+                    model: [qsTr("Any"), "TCP", "UDP", "UDPLite", "ICMP", "ICMPv6"]
                     Component.onCompleted: firewallTitle1.implicitWidth = width
                 }
                 RowLayout {
@@ -426,6 +426,12 @@ Pane {
                 ListElement {
                     name: qsTr("Drop")
                 }
+                ListElement {
+                    name: qsTr("Reject")
+                }
+                ListElement {
+                    name: qsTr("Ask")
+                }
             }
             textRole: "name"
             Component.onCompleted: {
@@ -442,11 +448,11 @@ Pane {
                 backend.rate_rules.dataChanged.connect((topLeft, bottomRight, roles) => {
                     console.assert(topLeft == bottomRight)
                     const name = backend.rate_rules.data(topLeft, Qt.UserRole)
-                    model.setProperty(2 + topLeft.row, "name", name.toString())
+                    model.setProperty(4 + topLeft.row, "name", name.toString())
                 })
                 backend.rate_rules.rowsRemoved.connect((_, first, last) => {
                     console.assert(first == last)
-                    model.remove(2 + first)
+                    model.remove(4 + first)
                 })
                 backend.rate_rules.rowsInserted.connect((_, first, last) => {
                     console.assert(first, last, model.count - 1)
@@ -156,6 +156,28 @@ Item {
                         anchors.horizontalCenter: parent.horizontalCenter
                     }
                 }
+                ToolSeparator {}
+                Pane {
+                    id: logsTitle7
+                    implicitWidth: defaultFont.width * 6
+                    padding: 0
+                    Label {
+                        text: "UID"
+                        font.bold: true
+                        anchors.horizontalCenter: parent.horizontalCenter
+                    }
+                }
+                ToolSeparator {}
+                Pane {
+                    id: logsTitle8
+                    implicitWidth: defaultFont.width * 6
+                    padding: 0
+                    Label {
+                        text: "Interface"
+                        font.bold: true
+                        anchors.horizontalCenter: parent.horizontalCenter
+                    }
+                }
             }
 
             ListView {
@@ -178,7 +200,14 @@ Item {
                         x: logsTitle1.x
                         width: logsTitle1.width
                         clip: true
-                        text: model.exe
+                        text: model.app_id ? model.exe + " (" + model.app_id + ")" : model.exe
+                        ToolTip.visible: model.owners && logExeArea.containsMouse
+                        ToolTip.text: qsTr("Any of ") + model.owners
+                        MouseArea {
+                            id: logExeArea
+                            anchors.fill: parent
+                            hoverEnabled: true
+                        }
                         anchors.verticalCenter: parent.verticalCenter
                     }
                     Label {
@@ -192,6 +221,13 @@ Item {
                         width: logsTitle3.width
                         clip: true
                         text: model.addr
+                        ToolTip.visible: logAddrArea.containsMouse
+                        ToolTip.text: qsTr("Local address ") + model.local_addr
+                        MouseArea {
+                            id: logAddrArea
+                            anchors.fill: parent
+                            hoverEnabled: true
+                        }
                         anchors.verticalCenter: parent.verticalCenter
                     }
                     Label {
@@ -207,6 +243,25 @@ Item {
                     Label {
                         x: logsTitle6.x
                         text: model.matched_rule != 0 ? model.matched_rule : qsTr("Default Rule")
+                        ToolTip.visible: model.matched_ancestor && logRuleArea.containsMouse
+                        ToolTip.text: qsTr("Inherited from ") + model.matched_ancestor
+                        MouseArea {
+                            id: logRuleArea
+                            anchors.fill: parent
+                            hoverEnabled: true
+                        }
+                        anchors.verticalCenter: parent.verticalCenter
+                    }
+                    Label {
+                        x: logsTitle7.x + logsTitle7.width - width
+                        text: model.uid
+                        anchors.verticalCenter: parent.verticalCenter
+                    }
+                    Label {
+                        x: logsTitle8.x
+                        width: logsTitle8.width
+                        clip: true
+                        text: model.interface
                         anchors.verticalCenter: parent.verticalCenter
                     }
                 }
@@ -108,6 +108,14 @@ ApplicationWindow {
             }
         }
     }
+    Connections {
+        target: backend
+        onExe_alert: {
+            errorPopup.message = message
+            errorPopup.error = exe
+            errorPopup.open()
+        }
+    }
     Popup {
         id: errorPopup
         property string message: ""
@@ -151,4 +159,53 @@ ApplicationWindow {
             }
         }
     }
+    Popup {
+        id: promptPopup
+        anchors.centerIn: Overlay.overlay
+        modal: true
+        closePolicy: Popup.NoAutoClose
+        visible: backend.has_prompt
+        Timer {
+            interval: 1000
+            repeat: true
+            running: backend.has_prompt
+            onTriggered: backend.expire_prompts()
+        }
+        ColumnLayout {
+            anchors.fill: parent
+            Label {
+                Layout.alignment: Qt.AlignHCenter
+                //: A program is connecting, asking for a verdict
+                text: qsTr("New Connection")
+                font.bold: true
+            }
+            MenuSeparator {
+                Layout.fillWidth: true
+            }
+            Label {
+                text: backend.prompt.exe + " (" + backend.prompt.pid + ")"
+                font.italic: true
+            }
+            Label {
+                text: (backend.prompt.input ? qsTr("From") : qsTr("To")) + " "
+                    + backend.prompt.addr + " " + backend.prompt.protocol
+            }
+            ComboBox {
+                id: promptRemember
+                Layout.fillWidth: true
+                model: [qsTr("Only this connection"), qsTr("Until the daemon restarts"), qsTr("Forever")]
+            }
+            RowLayout {
+                Layout.alignment: Qt.AlignRight
+                Button {
+                    text: qsTr("Deny")
+                    onClicked: backend.answer_prompt(false, promptRemember.currentIndex)
+                }
+                Button {
+                    text: qsTr("Allow")
+                    onClicked: backend.answer_prompt(true, promptRemember.currentIndex)
+                }
+            }
+        }
+    }
 }
@@ -1,5 +1,5 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::{TryFrom, TryInto};
 use std::env;
 use std::io;
@@ -12,12 +12,13 @@ use std::os::unix::net::UnixStream;
 use std::process::Command;
 use std::sync::atomic::Ordering;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use failure::{self, Fail};
 use futures::future::FutureExt;
 use gleipnir_interface::{
-    unixtransport, DaemonClient, Device, PackageReport, Proto, RateLimitRule, Rule, RuleTarget,
-    Rules,
+    unixtransport, ConnectionPrompt, DaemonClient, Device, ExeAlert, PackageReport, PromptAnswer,
+    Proto, RateLimitRule, Remember, Rule, RuleTarget, Rules,
 };
 use qmetaobject::*;
 use tarpc;
@@ -28,7 +29,7 @@ use crate::implementation;
 use crate::listmodel::{MutListItem, MutListModel};
 use crate::monitor;
 
-#[derive(QGadget, SimpleListItem, Default, Debug)]
+#[derive(QGadget, Default, Debug)]
 pub struct QRule {
     pub device: qt_property!(usize),
     pub proto: qt_property!(usize),
@@ -38,6 +39,8 @@ pub struct QRule {
     pub addr: qt_property!(QString),
     pub mask: qt_property!(u8),
     pub target: qt_property!(usize),
+    // the rule received from daemon, keeps fields that can't be edited here
+    raw: Rule,
 }
 
 impl From<&Rule> for QRule {
@@ -52,6 +55,8 @@ impl From<&Rule> for QRule {
             Some(Proto::Tcp) => 1,
             Some(Proto::Udp) => 2,
             Some(Proto::UdpLite) => 3,
+            Some(Proto::Icmp) => 4,
+            Some(Proto::Icmpv6) => 5,
         };
         let exe = rule
             .exe
@@ -71,7 +76,9 @@ impl From<&Rule> for QRule {
         let target = match rule.target {
             RuleTarget::Accept => 0,
             RuleTarget::Drop => 1,
-            RuleTarget::RateLimit(n) => n + 2,
+            RuleTarget::Reject => 2,
+            RuleTarget::Ask => 3,
+            RuleTarget::RateLimit(n) => n + 4,
         };
         Self {
             device,
@@ -82,6 +89,7 @@ impl From<&Rule> for QRule {
             addr,
             mask,
             target,
+            raw: rule.clone(),
         }
     }
 }
@@ -114,6 +122,8 @@ impl TryFrom<&QRule> for Rule {
             1 => Some(Proto::Tcp),
             2 => Some(Proto::Udp),
             3 => Some(Proto::UdpLite),
+            4 => Some(Proto::Icmp),
+            5 => Some(Proto::Icmpv6),
             _ => unreachable!(),
         };
         let exe = if !qrule.exe.to_slice().is_empty() {
@@ -139,7 +149,9 @@ impl TryFrom<&QRule> for Rule {
         let target = match qrule.target {
             0 => RuleTarget::Accept,
             1 => RuleTarget::Drop,
-            n => RuleTarget::RateLimit(n - 2),
+            2 => RuleTarget::Reject,
+            3 => RuleTarget::Ask,
+            n => RuleTarget::RateLimit(n - 4),
         };
         Ok(Self {
             device,
@@ -148,6 +160,7 @@ impl TryFrom<&QRule> for Rule {
             port,
             subnet,
             target,
+            ..qrule.raw.clone()
         })
     }
 }
@@ -221,8 +234,18 @@ pub struct Backend {
     pub charts: qt_property!(QVariantList; NOTIFY charts_changed),
     pub charts_changed: qt_signal!(),
     pub chart_x_size: qt_property!(usize),
+    pub prompt: qt_property!(QConnectionPrompt; NOTIFY prompt_changed),
+    pub has_prompt: qt_property!(bool; NOTIFY prompt_changed),
+    pub prompt_changed: qt_signal!(),
+    pub answer_prompt: qt_method!(fn(&mut self, accept: bool, remember: usize)),
+    pub expire_prompts: qt_method!(fn(&mut self)),
+    pub exe_alert: qt_signal!(message: QString, exe: QString),
+    // the first one is shown
+    prompts: VecDeque<(Instant, ConnectionPrompt)>,
     current_traffic: HashMap<String, ProgramStatus>,
     traffic_history: HashMap<String, Vec<u32>>,
+    // fields that can't be edited yet are sent back unchanged
+    last_rules: Rules,
     // prev_proc_on_chart: Vec<String>,
     runtime: Runtime,
     client: Option<DaemonClient>,
@@ -264,8 +287,16 @@ impl Backend {
             charts: Default::default(),
             charts_changed: Default::default(),
             chart_x_size: 80,
+            prompt: Default::default(),
+            has_prompt: false,
+            prompt_changed: Default::default(),
+            answer_prompt: Default::default(),
+            expire_prompts: Default::default(),
+            exe_alert: Default::default(),
+            prompts: Default::default(),
             current_traffic: Default::default(),
             traffic_history: Default::default(),
+            last_rules: Default::default(),
             // prev_proc_on_chart: vec![String::default(); 5],
             runtime,
             client: None,
@@ -287,13 +318,16 @@ impl Backend {
         let default_target = match self.default_target {
             0 => RuleTarget::Accept,
             1 => RuleTarget::Drop,
-            n => RuleTarget::RateLimit(n - 2),
+            2 => RuleTarget::Reject,
+            3 => RuleTarget::Ask,
+            n => RuleTarget::RateLimit(n - 4),
         };
 
         let rules = Rules {
             rules,
             rate_rules,
             default_target,
+            ..self.last_rules.clone()
         };
 
         dbg!(&rules);
@@ -310,7 +344,8 @@ impl Backend {
             .unwrap();
         dbg!(authed);
 
-        self.runtime
+        let result = self
+            .runtime
             .block_on(
                 self.client
                     .as_mut()
@@ -318,6 +353,68 @@ impl Backend {
                     .set_rules(tarpc::context::current(), rules),
             )
             .unwrap();
+        if let Err(e) = result {
+            self.apply_rules_error(e.into());
+        }
+    }
+
+    pub fn answer_prompt(&mut self, accept: bool, remember: usize) {
+        let (_, prompt) = match self.prompts.pop_front() {
+            Some(r) => r,
+            None => return,
+        };
+        let remember = match remember {
+            0 => Remember::Once,
+            1 => Remember::Temporary,
+            2 => Remember::Forever,
+            _ => unreachable!(),
+        };
+        let client = self.client.as_mut().expect("");
+        let authed = self
+            .runtime
+            .block_on(client.unlock(tarpc::context::current()).boxed())
+            .unwrap();
+        if authed {
+            self.runtime
+                .block_on(client.answer_prompt(
+                    tarpc::context::current(),
+                    prompt.id,
+                    PromptAnswer { accept, remember },
+                ))
+                .unwrap();
+        }
+        self.show_next_prompt();
+    }
+
+    /// Called by a timer while a prompt is shown, the daemon has given up on it after
+    /// `Rules::ask_timeout`
+    pub fn expire_prompts(&mut self) {
+        let timeout = self.prompt_timeout();
+        if let Some((received, _)) = self.prompts.front() {
+            if received.elapsed() >= timeout {
+                self.show_next_prompt();
+            }
+        }
+    }
+
+    fn prompt_timeout(&self) -> Duration {
+        Duration::from_secs(self.last_rules.ask_timeout.into())
+    }
+
+    fn show_next_prompt(&mut self) {
+        // the daemon has given up on them
+        let timeout = self.prompt_timeout();
+        while let Some((received, _)) = self.prompts.front() {
+            if received.elapsed() < timeout {
+                break;
+            }
+            self.prompts.pop_front();
+        }
+        self.has_prompt = !self.prompts.is_empty();
+        if let Some((_, prompt)) = self.prompts.front() {
+            self.prompt = prompt.into();
+        }
+        self.prompt_changed();
     }
 
     pub fn new_rate_rule(&mut self) {
@@ -380,9 +477,34 @@ impl Backend {
                     .expect("QObject doesn't exist");
             });
 
+            let ptr = QPointer::from(&*self);
+            let on_connection_prompt_callback = queued_callback(move |prompt| {
+                ptr.as_ref()
+                    .map(|p| {
+                        let mutp = unsafe { &mut *(p as *const _ as *mut implementation::Backend) };
+                        mutp.on_connection_prompt(prompt);
+                    })
+                    .expect("QObject doesn't exist");
+            });
+
+            let ptr = QPointer::from(&*self);
+            let on_exe_alert_callback = queued_callback(move |alert| {
+                ptr.as_ref()
+                    .map(|p| {
+                        let mutp = unsafe { &mut *(p as *const _ as *mut implementation::Backend) };
+                        mutp.on_exe_alert(alert);
+                    })
+                    .expect("QObject doesn't exist");
+            });
+
             thread::spawn(|| {
-                monitor::run(on_packages_callback, on_rules_updated_callback)
-                    .expect("Failed to start monitor");
+                monitor::run(
+                    on_packages_callback,
+                    on_rules_updated_callback,
+                    on_connection_prompt_callback,
+                    on_exe_alert_callback,
+                )
+                .expect("Failed to start monitor");
             });
             while !monitor::MONITOR_RUNNING.load(Ordering::Acquire) {}
         }
@@ -458,10 +580,11 @@ impl Backend {
         // TODO: impl extend_from_slice for SimpleListModel
         for log in &logs {
             self_logs.push(log.into());
+            let exe = display_exe(log);
             let status = self
                 .current_traffic
-                .entry(log.exe.clone())
-                .or_insert_with(|| ProgramStatus::new(&log.exe));
+                .entry(exe.to_owned())
+                .or_insert_with(|| ProgramStatus::new(exe));
             *status += log;
         }
     }
@@ -471,10 +594,69 @@ impl Backend {
         self.default_target = match rules.default_target {
             RuleTarget::Accept => 0,
             RuleTarget::Drop => 1,
-            RuleTarget::RateLimit(n) => n + 2,
+            RuleTarget::Reject => 2,
+            RuleTarget::Ask => 3,
+            RuleTarget::RateLimit(n) => n + 4,
         };
-        self.rate_rules.borrow_mut().reset_data(rules.rate_rules);
+        self.rate_rules
+            .borrow_mut()
+            .reset_data(rules.rate_rules.clone());
         self.default_target_changed();
+        self.last_rules = rules;
+    }
+    pub fn on_connection_prompt(&mut self, prompt: ConnectionPrompt) {
+        self.prompts.push_back((Instant::now(), prompt));
+        if self.prompts.len() == 1 {
+            self.show_next_prompt();
+        }
+    }
+    pub fn on_exe_alert(&mut self, alert: ExeAlert) {
+        let (message, exe) = match alert {
+            ExeAlert::HashMismatch { pid, exe, rule, .. } => (
+                format!(
+                    "Executable doesn't match the hash pinned by rule {}:",
+                    rule + 1
+                ),
+                format!("{} ({})", exe, pid),
+            ),
+            ExeAlert::Deleted { pid, exe } => (
+                "Executable has been replaced or removed, restart it:".to_owned(),
+                format!("{} ({})", exe, pid),
+            ),
+        };
+        self.exe_alert(message.into(), exe.into());
+    }
+}
+
+fn display_exe(log: &PackageReport) -> &str {
+    if log.unknown_process {
+        "[unknown]"
+    } else if let Some(script) = &log.script {
+        // rather than the interpreter
+        script
+    } else {
+        &log.exe
+    }
+}
+
+#[derive(QGadget, Default, Clone)]
+pub struct QConnectionPrompt {
+    pub pid: qt_property!(usize),
+    pub exe: qt_property!(QString),
+    pub input: qt_property!(bool),
+    pub protocol: qt_property!(QString),
+    pub addr: qt_property!(QString),
+}
+
+impl From<&'_ ConnectionPrompt> for QConnectionPrompt {
+    fn from(v: &ConnectionPrompt) -> Self {
+        Self {
+            pid: v.pid,
+            exe: v.exe.as_str().into(),
+            input: v.device.is_input(),
+            protocol: v.protocol.to_string().into(),
+            addr: v.addr.to_string().into(),
+        }
     }
 }
 
@@ -489,10 +671,18 @@ pub struct QPackageLog {
     pub dropped: bool,
     pub input: bool,
     pub exe: QString,
+    pub app_id: QString,
     pub protocol: QString,
     pub addr: QString,
+    pub local_addr: QString,
     pub len: usize,
     pub matched_rule: usize,
+    /// The process the rule matched, if not the one owning the socket
+    pub matched_ancestor: QString,
+    pub uid: QString,
+    pub interface: QString,
+    /// Owners of `SO_REUSEPORT` sockets of different programs, empty if there is only one
+    pub owners: QString,
 }
 
 impl From<&'_ PackageReport> for QPackageLog {
@@ -500,11 +690,33 @@ impl From<&'_ PackageReport> for QPackageLog {
         Self {
             dropped: v.dropped,
             input: v.device.is_input(),
-            exe: (&*v.exe).into(),
+            exe: display_exe(v).into(),
+            app_id: v.app_id.as_deref().unwrap_or_default().into(),
             protocol: v.protocol.to_string().into(),
             addr: v.addr.to_string().into(),
+            local_addr: v.local_addr.to_string().into(),
             len: v.len,
-            matched_rule: v.matched_rule.map(|x| x + 1).unwrap_or(0),
+            // rules of unknown processes are not listed
+            matched_rule: v
+                .matched_rule
+                .filter(|_| !v.unknown_process)
+                .map(|x| x + 1)
+                .unwrap_or(0),
+            matched_ancestor: v
+                .matched_ancestor
+                .as_ref()
+                .map(|a| format!("{} ({})", a.exe, a.pid))
+                .unwrap_or_default()
+                .into(),
+            uid: v.uid.map(|uid| uid.to_string()).unwrap_or_default().into(),
+            interface: v.interface.as_deref().unwrap_or_default().into(),
+            owners: v
+                .owners
+                .iter()
+                .map(|owner| format!("{} ({})", owner.exe, owner.pid))
+                .collect::<Vec<_>>()
+                .join(", ")
+                .into(),
         }
     }
 }
@@ -6,7 +6,9 @@ use futures::{
     future::{self, Ready},
     prelude::*,
 };
-use gleipnir_interface::{unixtransport, Monitor, PackageReport, Rules};
+use gleipnir_interface::{
+    unixtransport, ConnectionPrompt, ExeAlert, Monitor, PackageReport, Rules,
+};
 use tarpc::rpc::context::Context;
 use tarpc::server::Channel;
 use tokio_serde::formats::Bincode;
@@ -14,22 +16,30 @@ use tokio_serde::formats::Bincode;
 pub static MONITOR_RUNNING: AtomicBool = AtomicBool::new(false);
 
 #[derive(Clone)]
-struct MyMonitor<F0, F1>
+struct MyMonitor<F0, F1, F2, F3>
 where
     F0: Fn(Vec<PackageReport>) + Send + Sync + Clone + 'static,
     F1: Fn(Rules) + Send + Sync + Clone + 'static,
+    F2: Fn(ConnectionPrompt) + Send + Sync + Clone + 'static,
+    F3: Fn(ExeAlert) + Send + Sync + Clone + 'static,
 {
     on_packages: F0,
     on_rules_updated: F1,
+    on_connection_prompt: F2,
+    on_exe_alert: F3,
 }
 
-impl<F0, F1> Monitor for MyMonitor<F0, F1>
+impl<F0, F1, F2, F3> Monitor for MyMonitor<F0, F1, F2, F3>
 where
     F0: Fn(Vec<PackageReport>) + Send + Sync + Clone + 'static,
     F1: Fn(Rules) + Send + Sync + Clone + 'static,
+    F2: Fn(ConnectionPrompt) + Send + Sync + Clone + 'static,
+    F3: Fn(ExeAlert) + Send + Sync + Clone + 'static,
 {
     type OnPackagesFut = Ready<()>;
     type OnRulesUpdatedFut = Ready<()>;
+    type OnConnectionPromptFut = Ready<()>;
+    type OnExeAlertFut = Ready<()>;
     fn on_packages(self, _: Context, logs: Vec<PackageReport>) -> Self::OnPackagesFut {
         (self.on_packages)(logs);
         future::ready(())
@@ -38,12 +48,31 @@ where
         (self.on_rules_updated)(rules);
         future::ready(())
     }
+    fn on_connection_prompt(
+        self,
+        _: Context,
+        prompt: ConnectionPrompt,
+    ) -> Self::OnConnectionPromptFut {
+        (self.on_connection_prompt)(prompt);
+        future::ready(())
+    }
+    fn on_exe_alert(self, _: Context, alert: ExeAlert) -> Self::OnExeAlertFut {
+        (self.on_exe_alert)(alert);
+        future::ready(())
+    }
 }
 
-pub fn run<F0, F1>(on_packages: F0, on_rules_updated: F1) -> Result<(), std::io::Error>
+pub fn run<F0, F1, F2, F3>(
+    on_packages: F0,
+    on_rules_updated: F1,
+    on_connection_prompt: F2,
+    on_exe_alert: F3,
+) -> Result<(), std::io::Error>
 where
     F0: Fn(Vec<PackageReport>) + Send + Sync + Clone + 'static,
     F1: Fn(Rules) + Send + Sync + Clone + 'static,
+    F2: Fn(ConnectionPrompt) + Send + Sync + Clone + 'static,
+    F3: Fn(ExeAlert) + Send + Sync + Clone + 'static,
 {
     let addr = std::path::PathBuf::from("/tmp/gleipnir");
     if addr.exists() {
@@ -64,6 +93,8 @@ where
                 let server = MyMonitor {
                     on_packages: on_packages.clone(),
                     on_rules_updated: on_rules_updated.clone(),
+                    on_connection_prompt: on_connection_prompt.clone(),
+                    on_exe_alert: on_exe_alert.clone(),
                 };
                 channel.respond_with(server.serve()).execute()
             })
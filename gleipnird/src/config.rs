@@ -1,8 +1,10 @@
+use std::env;
 use std::fs::{create_dir_all, File};
 use std::path::PathBuf;
+use std::str::FromStr;
 
-use failure;
-use gleipnir_interface::{RuleTarget, Rules};
+use failure::{self, bail, format_err};
+use gleipnir_interface::Rules;
 use lazy_static::lazy_static;
 use serde_json;
 
@@ -17,6 +19,9 @@ lazy_static! {
 }
 
 pub fn save_rules(rules: &Rules) {
+    // temporary rules are forgotten when the daemon restarts
+    let mut rules = rules.clone();
+    rules.rules.retain(|rule| !rule.temporary);
     let r: Result<(), failure::Error> = try {
         let f = File::create(CONFIG_DIR.join("rules.json"))?;
         serde_json::to_writer(f, &rules)?;
@@ -29,12 +34,86 @@ pub fn save_rules(rules: &Rules) {
 pub fn load_rules() -> Result<Rules, failure::Error> {
     let path = CONFIG_DIR.join("rules.json");
     if !path.exists() {
-        return Ok(Rules {
-            default_target: RuleTarget::Accept,
-            rules: Default::default(),
-            rate_rules: Default::default(),
-        });
+        return Ok(Rules::default());
     }
     let f = File::open(path)?;
     Ok(serde_json::from_reader(f)?)
 }
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Backend {
+    /// Every packet goes through userspace
+    Nfqueue,
+    /// Verdicts of known sockets are cached in kernel, see `ebpf`
+    Ebpf,
+}
+
+impl FromStr for Backend {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfqueue" => Ok(Backend::Nfqueue),
+            "ebpf" => Ok(Backend::Ebpf),
+            _ => Err(format_err!("Unknown backend: {}", s)),
+        }
+    }
+}
+
+/// How the socket of a new flow is found, see `sockets`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SockDiagMode {
+    /// Up to three requests for every new flow
+    Query,
+    /// A table of all sockets, for hosts with lots of them
+    Dump,
+}
+
+impl FromStr for SockDiagMode {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "query" => Ok(SockDiagMode::Query),
+            "dump" => Ok(SockDiagMode::Dump),
+            _ => Err(format_err!("Unknown sock_diag mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub backend: Backend,
+    /// Number of queues, each one is handled by its own worker thread
+    pub queue_balance: u16,
+    pub sock_diag: SockDiagMode,
+    /// Compare the `sock_diag` modes and exit
+    pub bench_sock_diag: bool,
+}
+
+impl Options {
+    pub fn from_args() -> Result<Self, failure::Error> {
+        let mut options = Options {
+            backend: Backend::Nfqueue,
+            queue_balance: 1,
+            sock_diag: SockDiagMode::Query,
+            bench_sock_diag: false,
+        };
+        let mut args = env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format_err!("Missing value for {}", arg))
+            };
+            match &*arg {
+                "--backend" => options.backend = value()?.parse()?,
+                "--queue-balance" => options.queue_balance = value()?.parse()?,
+                "--sock-diag" => options.sock_diag = value()?.parse()?,
+                "--bench-sock-diag" => options.bench_sock_diag = true,
+                _ => bail!("Unknown argument: {}", arg),
+            }
+        }
+        if options.queue_balance == 0 {
+            bail!("--queue-balance must be at least 1");
+        }
+        Ok(options)
+    }
+}
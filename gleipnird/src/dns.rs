@@ -0,0 +1,526 @@
+//! Host names of remote addresses, snooped from DNS responses
+//!
+//! Only A and AAAA records are kept, under their own name and every name that leads to them
+//! through CNAME records. A response is only believed if the same socket has sent a query
+//! with its ID and name to the address it comes from, and both have been accepted, see
+//! `Cache::accepted`.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru_time_cache::LruCache;
+
+pub const PORT: u16 = 53;
+
+/// Programs often keep using an address after its TTL
+const MIN_TTL: Duration = Duration::from_secs(5 * 60);
+/// Names kept for an address, the oldest one is forgotten first
+const MAX_NAMES: usize = 16;
+/// CNAME chains longer than this are ignored
+const MAX_CNAME_DEPTH: usize = 8;
+/// Responses later than this are ignored, resolvers retry long before
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    /// The message ends in the middle of a record
+    Truncated,
+    /// Not exactly one question, or an invalid name
+    Malformed,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Query {
+    pub id: u16,
+    /// Of the question, lowercase, without the trailing dot
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Response {
+    pub query: Query,
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    Query(Query),
+    Response(Response),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Record {
+    /// Lowercase, without the trailing dot
+    pub name: String,
+    pub addr: IpAddr,
+    pub ttl: u32,
+}
+
+/// Shared by all workers, a response and the connection after it may be on different queues
+pub struct Cache {
+    names: Mutex<LruCache<IpAddr, Vec<(String, Instant)>>>,
+    /// Socket cookie and ID of the queries waiting for a response, to their name and resolver
+    queries: Mutex<LruCache<(u64, u16), (String, SocketAddr)>>,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Cache {
+            names: Mutex::new(LruCache::with_capacity(4096)),
+            queries: Mutex::new(LruCache::with_expiry_duration_and_capacity(
+                QUERY_TIMEOUT,
+                4096,
+            )),
+        }
+    }
+
+    /// A message sent to or received from `resolver` by the socket, once it's accepted
+    pub fn accepted(&self, cookie: u64, resolver: SocketAddr, msg: Message) {
+        match msg {
+            Message::Query(query) => {
+                let mut queries = self.queries.lock().unwrap();
+                queries.insert((cookie, query.id), (query.name, resolver));
+            }
+            Message::Response(response) => {
+                let key = (cookie, response.query.id);
+                let mut queries = self.queries.lock().unwrap();
+                // a forged one doesn't use up the query
+                let asked = match queries.get(&key) {
+                    Some((name, to)) => *name == response.query.name && *to == resolver,
+                    None => false,
+                };
+                if asked {
+                    queries.remove(&key);
+                    drop(queries);
+                    self.insert(response.records);
+                }
+            }
+        }
+    }
+
+    fn insert(&self, records: Vec<Record>) {
+        let now = Instant::now();
+        let mut cache = self.names.lock().unwrap();
+        for record in records {
+            let expire = now + Duration::from_secs(record.ttl.into()).max(MIN_TTL);
+            let mut names = cache.remove(&record.addr).unwrap_or_default();
+            names.retain(|(name, expire)| *expire > now && *name != record.name);
+            if names.len() >= MAX_NAMES {
+                names.remove(0);
+            }
+            names.push((record.name, expire));
+            cache.insert(record.addr, names);
+        }
+    }
+
+    /// Names of `addr` that haven't expired
+    pub fn names(&self, addr: IpAddr) -> Vec<String> {
+        let now = Instant::now();
+        match self.names.lock().unwrap().get(&addr) {
+            Some(names) => names
+                .iter()
+                .filter(|(_, expire)| *expire > now)
+                .map(|(name, _)| name.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// DNS over TCP prefixes every message with its length,
+/// a message split into multiple segments is not parsed
+pub fn parse_tcp(segment: &[u8]) -> Result<Message, ParseError> {
+    let len = match segment {
+        [len0, len1, ..] => u16::from_be_bytes([*len0, *len1]) as usize,
+        _ => return Err(ParseError::Truncated),
+    };
+    let msg = segment.get(2..2 + len).ok_or(ParseError::Truncated)?;
+    parse(msg)
+}
+
+/// Only the question of a query is parsed, what follows it may be cut off
+pub fn parse(msg: &[u8]) -> Result<Message, ParseError> {
+    const HEADER_LEN: usize = 12;
+    const QR_RESPONSE: u8 = 0x80;
+    if msg.len() < HEADER_LEN {
+        return Err(ParseError::Truncated);
+    }
+    let id = u16::from_be_bytes([msg[0], msg[1]]);
+    let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+    let ancount = u16::from_be_bytes([msg[6], msg[7]]);
+    // nobody sends more, and a response to none can't be told apart
+    if qdcount != 1 {
+        return Err(ParseError::Malformed);
+    }
+    let (name, next) = read_name(msg, HEADER_LEN)?;
+    let query = Query { id, name };
+    if msg[2] & QR_RESPONSE == 0 {
+        return Ok(Message::Query(query));
+    }
+    // type and class
+    let mut offset = next + 4;
+
+    let mut addrs = Vec::new();
+    // alias -> canonical name
+    let mut cnames: HashMap<String, String> = HashMap::new();
+    for _ in 0..ancount {
+        let (name, next) = read_name(msg, offset)?;
+        let fixed = msg.get(next..next + 10).ok_or(ParseError::Truncated)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+        let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let rdata_offset = next + 10;
+        let rdata = msg
+            .get(rdata_offset..rdata_offset + rdlength)
+            .ok_or(ParseError::Truncated)?;
+        offset = rdata_offset + rdlength;
+        if class != CLASS_IN {
+            continue;
+        }
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) => {
+                let addr = Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]);
+                addrs.push((name, IpAddr::from(addr), ttl));
+            }
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                addrs.push((name, IpAddr::from(Ipv6Addr::from(octets)), ttl));
+            }
+            (TYPE_CNAME, _) => {
+                // the name may point to anywhere in the message
+                let (canonical, _) = read_name(msg, rdata_offset)?;
+                cnames.insert(name, canonical);
+            }
+            (TYPE_A, _) | (TYPE_AAAA, _) => return Err(ParseError::Malformed),
+            _ => (),
+        }
+    }
+
+    let mut records = Vec::new();
+    for (name, addr, ttl) in addrs {
+        for alias in aliases(&cnames, &name) {
+            records.push(Record {
+                name: alias,
+                addr,
+                ttl,
+            });
+        }
+        records.push(Record { name, addr, ttl });
+    }
+    Ok(Message::Response(Response { query, records }))
+}
+
+/// Names that lead to `name` through CNAME records
+fn aliases(cnames: &HashMap<String, String>, name: &str) -> Vec<String> {
+    let mut found = vec![name.to_owned()];
+    for _ in 0..MAX_CNAME_DEPTH {
+        let before = found.len();
+        for (alias, canonical) in cnames {
+            if found.contains(canonical) && !found.contains(alias) {
+                found.push(alias.clone());
+            }
+        }
+        if found.len() == before {
+            break;
+        }
+    }
+    found.remove(0);
+    found
+}
+
+/// Returns the name, and the offset after it (not after the compression pointer target)
+fn read_name(msg: &[u8], mut offset: usize) -> Result<(String, usize), ParseError> {
+    const MAX_NAME_LEN: usize = 255;
+    const POINTER: u8 = 0xc0;
+    let mut name = String::new();
+    let mut end = None;
+    // pointers must go backward, so there can't be a loop
+    let mut limit = offset;
+    loop {
+        let len = *msg.get(offset).ok_or(ParseError::Truncated)?;
+        if len & POINTER == POINTER {
+            let low = *msg.get(offset + 1).ok_or(ParseError::Truncated)?;
+            let target = u16::from_be_bytes([len & !POINTER, low]) as usize;
+            if target >= limit {
+                return Err(ParseError::Malformed);
+            }
+            end.get_or_insert(offset + 2);
+            offset = target;
+            limit = target;
+            continue;
+        }
+        if len & POINTER != 0 {
+            // the extended label types are obsolete
+            return Err(ParseError::Malformed);
+        }
+        if len == 0 {
+            let end = end.unwrap_or(offset + 1);
+            return Ok((name, end));
+        }
+        let label = msg
+            .get(offset + 1..offset + 1 + len as usize)
+            .ok_or(ParseError::Truncated)?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+        if name.len() > MAX_NAME_LEN {
+            return Err(ParseError::Malformed);
+        }
+        offset += 1 + len as usize;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut r = Vec::new();
+        for label in name.split('.') {
+            r.push(label.len() as u8);
+            r.extend_from_slice(label.as_bytes());
+        }
+        r.push(0);
+        r
+    }
+
+    fn record(name: &[u8], rtype: u16, ttl: u32, rdata: &[u8]) -> Vec<u8> {
+        let mut r = name.to_vec();
+        r.extend_from_slice(&rtype.to_be_bytes());
+        r.extend_from_slice(&CLASS_IN.to_be_bytes());
+        r.extend_from_slice(&ttl.to_be_bytes());
+        r.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        r.extend_from_slice(rdata);
+        r
+    }
+
+    fn response(question: &str, answers: &[Vec<u8>]) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x81, 0x80, 0, 1];
+        msg.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&[0, 0, 0, 0]);
+        msg.extend_from_slice(&name(question));
+        msg.extend_from_slice(&[0, 1, 0, 1]);
+        for answer in answers {
+            msg.extend_from_slice(answer);
+        }
+        msg
+    }
+
+    /// Points to the question name
+    const QUESTION: [u8; 2] = [0xc0, 12];
+
+    fn parse_response(msg: &[u8]) -> Result<Vec<Record>, ParseError> {
+        match parse(msg)? {
+            Message::Response(response) => Ok(response.records),
+            Message::Query(_) => panic!("not a response"),
+        }
+    }
+
+    #[test]
+    fn address_records() {
+        let msg = response(
+            "Example.com",
+            &[
+                record(&QUESTION, TYPE_A, 60, &[93, 184, 216, 34]),
+                record(
+                    &QUESTION,
+                    TYPE_AAAA,
+                    60,
+                    &[0x26, 0x06, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1],
+                ),
+            ],
+        );
+        let records = parse_response(&msg).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                Record {
+                    name: "example.com".to_owned(),
+                    addr: [93, 184, 216, 34].into(),
+                    ttl: 60,
+                },
+                Record {
+                    name: "example.com".to_owned(),
+                    addr: "2606::1".parse().unwrap(),
+                    ttl: 60,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn cname_chain() {
+        // www.example.com -> cdn.example.net -> edge.cdn.example.net -> A
+        let cdn = name("cdn.example.net");
+        let msg = response(
+            "www.example.com",
+            &[
+                record(&QUESTION, TYPE_CNAME, 300, &cdn),
+                // "edge." + pointer to "cdn.example.net" in the rdata above
+                record(
+                    &[0xc0, 45],
+                    TYPE_CNAME,
+                    300,
+                    &[4, b'e', b'd', b'g', b'e', 0xc0, 45],
+                ),
+                record(
+                    &[4, b'e', b'd', b'g', b'e', 0xc0, 45],
+                    TYPE_A,
+                    20,
+                    &[1, 2, 3, 4],
+                ),
+            ],
+        );
+        let mut names: Vec<_> = parse_response(&msg)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["cdn.example.net", "edge.cdn.example.net", "www.example.com"]
+        );
+    }
+
+    #[test]
+    fn tcp() {
+        let msg = response(
+            "example.com",
+            &[record(&QUESTION, TYPE_A, 60, &[1, 1, 1, 1])],
+        );
+        let mut segment = (msg.len() as u16).to_be_bytes().to_vec();
+        segment.extend_from_slice(&msg);
+        match parse_tcp(&segment).unwrap() {
+            Message::Response(response) => assert_eq!(response.records.len(), 1),
+            Message::Query(_) => panic!("not a query"),
+        }
+        assert_eq!(
+            parse_tcp(&segment[..segment.len() - 1]),
+            Err(ParseError::Truncated)
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let mut msg = response("example.com", &[]);
+        msg[5] = 2;
+        assert_eq!(parse(&msg), Err(ParseError::Malformed));
+        msg[5] = 0;
+        assert_eq!(parse(&msg), Err(ParseError::Malformed));
+
+        // a pointer to itself
+        let msg = response(
+            "example.com",
+            &[record(&[0xc0, 29], TYPE_A, 60, &[1, 1, 1, 1])],
+        );
+        assert_eq!(parse_response(&msg), Err(ParseError::Malformed));
+
+        let msg = response(
+            "example.com",
+            &[record(&QUESTION, TYPE_A, 60, &[1, 1, 1, 1])],
+        );
+        assert_eq!(
+            parse_response(&msg[..msg.len() - 2]),
+            Err(ParseError::Truncated)
+        );
+    }
+
+    #[test]
+    fn cache() {
+        let cache = Cache::new();
+        let addr: IpAddr = [1, 1, 1, 1].into();
+        let record = |name: &str| Record {
+            name: name.to_owned(),
+            addr,
+            ttl: 0,
+        };
+        cache.insert(vec![record("a.example.com"), record("b.example.com")]);
+        cache.insert(vec![record("a.example.com")]);
+        assert_eq!(cache.names(addr), vec!["b.example.com", "a.example.com"]);
+        assert!(cache.names([2, 2, 2, 2].into()).is_empty());
+    }
+
+    #[test]
+    fn query() {
+        // cut off after the question, like by the copy range
+        let mut msg = response("Example.com", &[]);
+        msg[2] = 0x01;
+        msg.extend_from_slice(&[0, 0x29]);
+        assert_eq!(
+            parse(&msg),
+            Ok(Message::Query(Query {
+                id: 0x1234,
+                name: "example.com".to_owned(),
+            }))
+        );
+    }
+
+    #[test]
+    fn only_responses_to_queries() {
+        let cache = Cache::new();
+        let resolver: SocketAddr = ([9, 9, 9, 9], PORT).into();
+        let query = |id, name: &str| {
+            Message::Query(Query {
+                id,
+                name: name.to_owned(),
+            })
+        };
+        let response = |id, name: &str, addr: [u8; 4]| {
+            Message::Response(Response {
+                query: Query {
+                    id,
+                    name: name.to_owned(),
+                },
+                records: vec![Record {
+                    name: name.to_owned(),
+                    addr: addr.into(),
+                    ttl: 60,
+                }],
+            })
+        };
+
+        // nothing asked
+        cache.accepted(1, resolver, response(7, "example.com", [1, 1, 1, 1]));
+        cache.accepted(1, resolver, query(7, "example.com"));
+        // another socket, ID, name or resolver
+        cache.accepted(2, resolver, response(7, "example.com", [2, 2, 2, 2]));
+        cache.accepted(1, resolver, response(8, "example.com", [3, 3, 3, 3]));
+        cache.accepted(1, resolver, response(7, "bank.com", [4, 4, 4, 4]));
+        cache.accepted(
+            1,
+            ([6, 6, 6, 6], PORT).into(),
+            response(7, "example.com", [5, 5, 5, 5]),
+        );
+        for addr in &[
+            [1, 1, 1, 1],
+            [2, 2, 2, 2],
+            [3, 3, 3, 3],
+            [4, 4, 4, 4],
+            [5, 5, 5, 5],
+        ] {
+            assert!(cache.names((*addr).into()).is_empty());
+        }
+
+        // the forged ones didn't use it up
+        cache.accepted(1, resolver, response(7, "example.com", [8, 8, 8, 8]));
+        assert_eq!(cache.names([8, 8, 8, 8].into()), vec!["example.com"]);
+
+        cache.accepted(1, resolver, query(9, "example.com"));
+        cache.accepted(1, resolver, response(9, "example.com", [6, 6, 6, 6]));
+        assert_eq!(cache.names([6, 6, 6, 6].into()), vec!["example.com"]);
+        // only once
+        cache.accepted(1, resolver, response(9, "example.com", [7, 7, 7, 7]));
+        assert!(cache.names([7, 7, 7, 7].into()).is_empty());
+    }
+}
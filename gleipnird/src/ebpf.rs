@@ -0,0 +1,531 @@
+//! A verdict cache in cgroup_skb programs
+//!
+//! Two tiny programs (ingress and egress) are attached to the root cgroup, they look up the
+//! socket cookie of every packet in a map filled by the daemon. A socket is added to the map
+//! once its first packet got a verdict from the rules, and its connection is marked with
+//! `Verdicts::mark` so netfilter stops queueing it (see `netfilter::QueueMode::Uncached`).
+//! The rules themselves are only matched in userspace.
+//!
+//! The programs run after netfilter, so they can't send a packet to userspace. A socket
+//! missing from the map is one whose packets are still queued, the connections older than
+//! the daemon and the ones that didn't fit in the map included, and the packet has been
+//! accepted there already.
+//!
+//! The rules are not matched by the programs. Most of what they match on (executables,
+//! ancestors, scripts, hashes, app IDs, host names) is found in `/proc` or snooped DNS
+//! responses by the daemon, it's not known to a cgroup_skb program.
+//!
+//! The programs are assembled by hand, there is no need for clang or libbpf.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gleipnir_interface::{Ancestor, Device, PacketInfo, Proto, RuleTarget};
+use libc;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+
+use crate::netfilter;
+use crate::netlink::{SockDestroyed, SockDiag};
+use crate::rules::IndexedRules;
+
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_int = 3;
+const BPF_MAP_GET_NEXT_KEY: libc::c_int = 4;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_PROG_ATTACH: libc::c_int = 8;
+const BPF_PROG_DETACH: libc::c_int = 9;
+
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_PROG_TYPE_CGROUP_SKB: u32 = 8;
+const BPF_CGROUP_INET_INGRESS: u32 = 0;
+const BPF_CGROUP_INET_EGRESS: u32 = 1;
+const BPF_F_ALLOW_MULTI: u32 = 1 << 1;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_ANY: u64 = 0;
+const BPF_EXIST: u64 = 2;
+
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_FUNC_GET_SOCKET_COOKIE: i32 = 46;
+
+/// Sockets are only removed by the daemon, a socket that doesn't fit is not cached
+const MAX_SOCKETS: u32 = 65536;
+/// How often sockets closed without an event (raw and ping sockets, lost events) are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+const VERDICT_DROP: u32 = 0;
+const VERDICT_ACCEPT: u32 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BpfInsn {
+    code: u8,
+    regs: u8, // dst_reg: 4, src_reg: 4
+    off: i16,
+    imm: i32,
+}
+
+impl BpfInsn {
+    const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        BpfInsn {
+            code,
+            regs: (src << 4) | dst,
+            off,
+            imm,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapElemAttr {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64, // or next_key
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgAttachAttr {
+    target_fd: u32,
+    attach_bpf_fd: u32,
+    attach_type: u32,
+    attach_flags: u32,
+}
+
+unsafe fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> Result<libc::c_long, io::Error> {
+    let r = libc::syscall(
+        libc::SYS_bpf,
+        cmd,
+        attr as *mut T,
+        mem::size_of::<T>() as libc::c_uint,
+    );
+    if r < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(r)
+    }
+}
+
+/// What the verdict of a socket was made from, an owned `PacketInfo`
+#[derive(Debug, Clone)]
+pub struct Flow {
+    device: Device,
+    protocol: Proto,
+    addr: SocketAddr,
+    local_addr: SocketAddr,
+    icmp: Option<(u8, u8)>,
+    interface: Option<String>,
+    exe: String,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    cgroup: String,
+    app_id: Option<String>,
+    args: Vec<String>,
+    ancestors: Vec<Ancestor>,
+    exe_hash: Option<String>,
+    names: Vec<String>,
+}
+
+impl Flow {
+    pub fn new(packet: &PacketInfo) -> Self {
+        Flow {
+            device: packet.device,
+            protocol: packet.protocol,
+            addr: packet.addr,
+            local_addr: packet.local_addr,
+            icmp: packet.icmp,
+            interface: packet.interface.map(str::to_owned),
+            exe: packet.exe.to_owned(),
+            uid: packet.uid,
+            gid: packet.gid,
+            cgroup: packet.cgroup.to_owned(),
+            app_id: packet.app_id.map(str::to_owned),
+            args: packet.args.to_vec(),
+            ancestors: packet.ancestors.to_vec(),
+            exe_hash: packet.exe_hash.map(str::to_owned),
+            names: packet.names.to_vec(),
+        }
+    }
+
+    fn packet(&self) -> PacketInfo {
+        PacketInfo {
+            device: self.device,
+            protocol: self.protocol,
+            addr: self.addr,
+            local_addr: self.local_addr,
+            icmp: self.icmp,
+            interface: self.interface.as_deref(),
+            exe: &self.exe,
+            uid: self.uid,
+            gid: self.gid,
+            cgroup: &self.cgroup,
+            app_id: self.app_id.as_deref(),
+            args: &self.args,
+            ancestors: &self.ancestors,
+            exe_hash: self.exe_hash.as_deref(),
+            names: &self.names,
+        }
+    }
+}
+
+pub struct Verdicts {
+    map_fd: RawFd,
+    cgroup_fd: RawFd,
+    programs: [(u32, RawFd); 2],
+    flows: Mutex<HashMap<u64, Flow>>,
+    mark: u32,
+}
+
+impl Verdicts {
+    /// Load the programs and attach them to the root cgroup v2, closed sockets are
+    /// forgotten by a thread of its own, see `remove_closed`
+    pub fn attach() -> Result<Arc<Self>, io::Error> {
+        let map_fd = create_map()?;
+        let cgroup = [
+            Path::new("/sys/fs/cgroup/unified"), // hybrid hierarchy
+            Path::new("/sys/fs/cgroup"),
+        ]
+        .iter()
+        .copied()
+        .find(|path| path.exists())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?
+        .to_str()
+        .expect("cgroup path not a vaild UTF-8");
+        let cgroup = CString::new(cgroup).expect("cgroup path contains NUL");
+        let cgroup_fd = unsafe { libc::open(cgroup.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY) };
+        if cgroup_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let programs = [
+            (
+                BPF_CGROUP_INET_INGRESS,
+                load_program(map_fd, BPF_CGROUP_INET_INGRESS)?,
+            ),
+            (
+                BPF_CGROUP_INET_EGRESS,
+                load_program(map_fd, BPF_CGROUP_INET_EGRESS)?,
+            ),
+        ];
+        for &(attach_type, prog_fd) in &programs {
+            let mut attr = ProgAttachAttr {
+                target_fd: cgroup_fd as u32,
+                attach_bpf_fd: prog_fd as u32,
+                attach_type,
+                // don't replace programs from others
+                attach_flags: BPF_F_ALLOW_MULTI,
+            };
+            unsafe { bpf(BPF_PROG_ATTACH, &mut attr)? };
+        }
+        let verdicts = Arc::new(Verdicts {
+            map_fd,
+            cgroup_fd,
+            programs,
+            flows: Default::default(),
+            // connections cached by an earlier daemon are queued again
+            mark: netfilter::verdict_mark(
+                netfilter::MARK_EBPF,
+                RandomState::new().build_hasher().finish() as u32,
+            ),
+        });
+        let destroyed = match SockDestroyed::new() {
+            Ok(destroyed) => Some(destroyed),
+            Err(e) => {
+                eprintln!(
+                    "WARNING: no socket events, closed sockets are kept in the eBPF map until the next sweep: {}",
+                    e
+                );
+                None
+            }
+        };
+        let diag = SockDiag::new()?;
+        let watched = verdicts.clone();
+        thread::spawn(move || watched.remove_closed(diag, destroyed));
+        Ok(verdicts)
+    }
+
+    pub fn detach(&self) {
+        for &(attach_type, prog_fd) in &self.programs {
+            let mut attr = ProgAttachAttr {
+                target_fd: self.cgroup_fd as u32,
+                attach_bpf_fd: prog_fd as u32,
+                attach_type,
+                attach_flags: 0,
+            };
+            if let Err(e) = unsafe { bpf(BPF_PROG_DETACH, &mut attr) } {
+                dbg!(e);
+            }
+        }
+    }
+
+    /// Conntrack mark of connections whose sockets are in the map
+    pub fn mark(&self) -> u32 {
+        self.mark
+    }
+
+    /// Let the kernel handle the rest of packets of this socket, fails with `E2BIG` when
+    /// the map is full
+    pub fn set(&self, cookie: u64, flow: Flow, accept: bool) -> Result<(), io::Error> {
+        self.update(cookie, accept, BPF_ANY)?;
+        self.flows.lock().unwrap().insert(cookie, flow);
+        Ok(())
+    }
+
+    fn update(&self, cookie: u64, accept: bool, flags: u64) -> Result<(), io::Error> {
+        let value = if accept { VERDICT_ACCEPT } else { VERDICT_DROP };
+        let mut attr = MapElemAttr {
+            map_fd: self.map_fd as u32,
+            key: &cookie as *const u64 as u64,
+            value: &value as *const u32 as u64,
+            flags,
+        };
+        unsafe { bpf(BPF_MAP_UPDATE_ELEM, &mut attr)? };
+        Ok(())
+    }
+
+    pub fn remove(&self, cookie: u64) {
+        self.flows.lock().unwrap().remove(&cookie);
+        self.delete(cookie);
+    }
+
+    fn delete(&self, cookie: u64) {
+        let mut attr = MapElemAttr {
+            map_fd: self.map_fd as u32,
+            key: &cookie as *const u64 as u64,
+            ..Default::default()
+        };
+        // ENOENT is fine
+        let _ = unsafe { bpf(BPF_MAP_DELETE_ELEM, &mut attr) };
+    }
+
+    /// Apply new rules to sockets already known by the kernel
+    ///
+    /// Established connections never come back to userspace, so a socket that becomes
+    /// rate-limited is accepted by the kernel from now on.
+    pub fn refresh(&self, rules: &IndexedRules) {
+        // the map is updated without holding the lock, it's thousands of syscalls
+        let verdicts: Vec<(u64, bool)> = {
+            let flows = self.flows.lock().unwrap();
+            flows
+                .iter()
+                .filter_map(|(&cookie, flow)| {
+                    let accept = match rules.match_target(&flow.packet()).1 {
+                        RuleTarget::Accept | RuleTarget::RateLimit(_) => true,
+                        // there is nothing to reject with, it's an established connection
+                        RuleTarget::Drop | RuleTarget::Reject => false,
+                        // it was answered when it was established, keep the verdict
+                        RuleTarget::Ask => return None,
+                    };
+                    Some((cookie, accept))
+                })
+                .collect()
+        };
+        for (cookie, accept) in verdicts {
+            // not again if it was closed in the meantime
+            match self.update(cookie, accept, BPF_EXIST) {
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => (),
+                Err(e) => {
+                    dbg!(e);
+                }
+                Ok(()) => (),
+            }
+        }
+    }
+
+    /// Forgets sockets as they are closed, and every `SWEEP_INTERVAL` the ones closed
+    /// without telling
+    fn remove_closed(&self, mut diag: SockDiag, mut destroyed: Option<SockDestroyed>) {
+        loop {
+            if let Err(e) = self.sweep(&mut diag) {
+                eprintln!("ERROR: eBPF verdicts sweep: {}", e);
+            }
+            let next_sweep = Instant::now() + SWEEP_INTERVAL;
+            loop {
+                let left = next_sweep.saturating_duration_since(Instant::now());
+                if left == Duration::from_secs(0) {
+                    break;
+                }
+                let events = match &mut destroyed {
+                    Some(events) => events,
+                    None => {
+                        thread::sleep(left);
+                        continue;
+                    }
+                };
+                let mut fds = [PollFd::new(events.as_raw_fd(), PollFlags::POLLIN)];
+                match poll(&mut fds, left.as_millis() as i32) {
+                    Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                    Err(e) => panic!("{}", e),
+                }
+                match events.recv() {
+                    Ok(closed) => {
+                        for diag_msg in closed {
+                            self.remove(diag_msg.cookie());
+                        }
+                    }
+                    // some events were lost, sweep now
+                    Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => break,
+                    Err(e) => {
+                        eprintln!("ERROR: socket events: {}", e);
+                        destroyed = None;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes the sockets that are not alive anymore, from the map and `flows`
+    fn sweep(&self, diag: &mut SockDiag) -> Result<(), io::Error> {
+        // taken before the dump, so every socket that is still alive is found by it
+        let mut known = self.keys();
+        known.extend(self.flows.lock().unwrap().keys());
+        let mut alive = HashSet::new();
+        for &(protocol, ipv4) in &[
+            (Proto::Tcp, true),
+            (Proto::Tcp, false),
+            (Proto::Udp, true),
+            (Proto::Udp, false),
+            (Proto::Icmp, true),
+            (Proto::Icmpv6, false),
+        ] {
+            alive.extend(diag.dump(protocol, ipv4)?.iter().map(|s| s.cookie()));
+        }
+        for cookie in known.difference(&alive) {
+            self.remove(*cookie);
+        }
+        Ok(())
+    }
+
+    /// The cookies in the map
+    fn keys(&self) -> HashSet<u64> {
+        let mut keys = HashSet::new();
+        let mut key = 0u64;
+        let mut prev_key: Option<u64> = None;
+        loop {
+            let mut attr = MapElemAttr {
+                map_fd: self.map_fd as u32,
+                key: prev_key
+                    .as_ref()
+                    .map(|k| k as *const u64 as u64)
+                    .unwrap_or(0), // NULL, get the first key
+                value: &mut key as *mut u64 as u64,
+                ..Default::default()
+            };
+            if unsafe { bpf(BPF_MAP_GET_NEXT_KEY, &mut attr) }.is_err() {
+                break;
+            }
+            keys.insert(key);
+            prev_key = Some(key);
+        }
+        keys
+    }
+}
+
+impl Drop for Verdicts {
+    fn drop(&mut self) {
+        unsafe {
+            for &(_, prog_fd) in &self.programs {
+                libc::close(prog_fd);
+            }
+            libc::close(self.cgroup_fd);
+            libc::close(self.map_fd);
+        }
+    }
+}
+
+fn create_map() -> Result<RawFd, io::Error> {
+    let mut attr = MapCreateAttr {
+        map_type: BPF_MAP_TYPE_HASH,
+        key_size: mem::size_of::<u64>() as u32, // socket cookie
+        value_size: mem::size_of::<u32>() as u32, // VERDICT_*
+        max_entries: MAX_SOCKETS,
+        map_flags: 0,
+    };
+    Ok(unsafe { bpf(BPF_MAP_CREATE, &mut attr)? } as RawFd)
+}
+
+fn load_program(map_fd: RawFd, attach_type: u32) -> Result<RawFd, io::Error> {
+    // r0 = bpf_get_socket_cookie(skb)
+    // *(u64 *)(r10 - 8) = r0
+    // r0 = bpf_map_lookup_elem(map, r10 - 8)
+    // if r0 == NULL: return 1, it was queued
+    // return *(u32 *)r0 & 1
+    let insns = [
+        BpfInsn::new(0x85, 0, 0, 0, BPF_FUNC_GET_SOCKET_COOKIE), // call
+        BpfInsn::new(0x7b, 10, 0, -8, 0),                        // stxdw
+        BpfInsn::new(0x18, 1, BPF_PSEUDO_MAP_FD, 0, map_fd),     // lddw
+        BpfInsn::new(0x00, 0, 0, 0, 0),
+        BpfInsn::new(0xbf, 2, 10, 0, 0), // mov64 r2, r10
+        BpfInsn::new(0x07, 2, 0, 0, -8), // add64 r2, -8
+        BpfInsn::new(0x85, 0, 0, 0, BPF_FUNC_MAP_LOOKUP_ELEM), // call
+        BpfInsn::new(0x15, 0, 0, 3, 0),  // jeq r0, 0, +3
+        BpfInsn::new(0x61, 0, 0, 0, 0),  // ldxw r0, [r0]
+        BpfInsn::new(0x57, 0, 0, 0, 1),  // and64 r0, 1
+        BpfInsn::new(0x95, 0, 0, 0, 0),  // exit
+        BpfInsn::new(0xb7, 0, 0, 0, VERDICT_ACCEPT as i32), // mov64 r0, 1
+        BpfInsn::new(0x95, 0, 0, 0, 0),  // exit
+    ];
+    let license = b"GPL\0"; // required by bpf_get_socket_cookie
+    let mut log = vec![0u8; 4096];
+    let mut prog_name = [0u8; 16];
+    prog_name[..8].copy_from_slice(b"gleipnir");
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_CGROUP_SKB,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 1,
+        log_size: log.len() as u32,
+        log_buf: log.as_mut_ptr() as u64,
+        prog_name,
+        expected_attach_type: attach_type,
+        ..Default::default()
+    };
+    unsafe { bpf(BPF_PROG_LOAD, &mut attr) }
+        .map(|fd| fd as RawFd)
+        .map_err(|e| {
+            let end = log.iter().position(|&b| b == 0).unwrap_or(log.len());
+            eprintln!("{}", String::from_utf8_lossy(&log[..end]));
+            e
+        })
+}
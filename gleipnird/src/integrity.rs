@@ -0,0 +1,154 @@
+//! Hashes of executables pinned by `Rule::sha256`, and alerts about them
+//!
+//! Executables are read through `/proc/<pid>/exe`, that's the file the process is running
+//! even if its path has been replaced since. They are hashed by a thread of their own, packets
+//! of a process are held until its executable is hashed, see `worker`. Hashes are cached by
+//! process, and by device, inode, modification and change time, so a file written in place is
+//! hashed again even if its modification time is set back.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crossbeam_channel::{self, Receiver, Sender};
+use gleipnir_interface::ExeAlert;
+use lru_time_cache::LruCache;
+use sha2::{Digest, Sha256};
+
+use crate::proc::{self, ProcId};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+struct FileId {
+    dev: u64,
+    ino: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+    /// Can't be set by `utimensat`
+    ctime: i64,
+    ctime_nsec: i64,
+}
+
+/// A process and its executable, which changes on exec
+type Key = (ProcId, String);
+
+pub enum Hash {
+    /// Waiting for the hashing thread
+    Pending,
+    /// Hex SHA-256, `None` if it can't be read
+    Done(Option<String>),
+}
+
+/// Shared by all workers
+pub struct Integrity {
+    hashes: Mutex<LruCache<Key, Option<String>>>,
+    queued: Mutex<HashSet<Key>>,
+    requests: Sender<Key>,
+    /// Incremented whenever a hash is done
+    done: AtomicUsize,
+    /// PID, start time and the kind of alerts already sent
+    alerted: Mutex<LruCache<(usize, u64, u8), ()>>,
+    alerts: Sender<ExeAlert>,
+}
+
+impl Integrity {
+    pub fn new() -> (Arc<Self>, Receiver<ExeAlert>) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (requests, pending) = crossbeam_channel::unbounded();
+        let integrity = Arc::new(Integrity {
+            hashes: Mutex::new(LruCache::with_capacity(1024)),
+            queued: Mutex::new(HashSet::new()),
+            requests,
+            done: AtomicUsize::new(0),
+            alerted: Mutex::new(LruCache::with_capacity(1024)),
+            alerts: sender,
+        });
+        let hasher = integrity.clone();
+        thread::spawn(move || hasher.hash_all(pending));
+        (integrity, receiver)
+    }
+
+    /// The hash of the executable of a process, it's hashed by another thread the first time
+    pub fn hash(&self, proc: ProcId, exe: &str) -> Hash {
+        if proc.pid == 0 {
+            return Hash::Done(None);
+        }
+        let key = (proc, exe.to_owned());
+        if let Some(hash) = self.hashes.lock().unwrap().get(&key) {
+            return Hash::Done(hash.clone());
+        }
+        if self.queued.lock().unwrap().insert(key.clone()) {
+            self.requests.send(key).expect("hasher dead");
+        }
+        Hash::Pending
+    }
+
+    /// Changes whenever a pending hash is done
+    pub fn done(&self) -> usize {
+        self.done.load(Ordering::Acquire)
+    }
+
+    fn hash_all(&self, requests: Receiver<Key>) {
+        let mut files = LruCache::with_capacity(1024);
+        for key in requests {
+            let hash = hash_exe(key.0, &mut files);
+            self.hashes.lock().unwrap().insert(key.clone(), hash);
+            self.queued.lock().unwrap().remove(&key);
+            self.done.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// Only the first alert of each kind about a process is sent
+    pub fn alert(&self, proc: ProcId, alert: ExeAlert) {
+        let kind = match alert {
+            ExeAlert::HashMismatch { .. } => 0,
+            ExeAlert::Deleted { .. } => 1,
+        };
+        let key = (proc.pid, proc.starttime, kind);
+        let mut alerted = self.alerted.lock().unwrap();
+        if alerted.get(&key).is_none() {
+            alerted.insert(key, ());
+            self.alerts.send(alert).expect("alert service dead");
+        }
+    }
+}
+
+fn hash_exe(proc: ProcId, files: &mut LruCache<FileId, String>) -> Option<String> {
+    let mut file = File::open(format!("/proc/{}/exe", proc.pid)).ok()?;
+    // the PID may have been reused before it was opened
+    if !proc::is_alive(proc) {
+        return None;
+    }
+    let meta = file.metadata().ok()?;
+    let id = FileId {
+        dev: meta.dev(),
+        ino: meta.ino(),
+        mtime: meta.mtime(),
+        mtime_nsec: meta.mtime_nsec(),
+        ctime: meta.ctime(),
+        ctime_nsec: meta.ctime_nsec(),
+    };
+    if let Some(hash) = files.get(&id) {
+        return Some(hash.clone());
+    }
+    let hash = sha256(&mut file).ok()?;
+    files.insert(id, hash.clone());
+    Some(hash)
+}
+
+fn sha256(file: &mut File) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
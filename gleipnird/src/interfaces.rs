@@ -0,0 +1,84 @@
+//! Names of network interfaces, kept current by rtnetlink events, see `watch`
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::thread;
+
+use lazy_static::lazy_static;
+
+use crate::netlink::{LinkEvent, LinkMonitor};
+
+lazy_static! {
+    static ref NAMES: RwLock<HashMap<u32, String>> = RwLock::new(HashMap::new());
+}
+
+/// Link events keep `NAMES` current, names are not cached otherwise
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
+/// `None` if the interface is gone already
+pub fn name(index: u32) -> Option<String> {
+    if index == 0 {
+        return None;
+    }
+    if let Some(name) = NAMES.read().unwrap().get(&index) {
+        return Some(name.clone());
+    }
+    // created before its event came
+    let name = index_to_name(index)?;
+    if WATCHING.load(Ordering::Acquire) {
+        NAMES.write().unwrap().insert(index, name.clone());
+    }
+    Some(name)
+}
+
+fn index_to_name(index: u32) -> Option<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let r = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+    if r.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Subscribe to link events, so interfaces renamed or removed are noticed
+pub fn watch() -> io::Result<()> {
+    let mut monitor = LinkMonitor::new()?;
+    // after subscribing, so nothing is missed in between
+    monitor.dump()?;
+    WATCHING.store(true, Ordering::Release);
+    thread::spawn(move || loop {
+        match monitor.recv() {
+            Ok(events) => {
+                let mut names = NAMES.write().unwrap();
+                for event in events {
+                    match event {
+                        LinkEvent::New { index, name } => names.insert(index, name),
+                        LinkEvent::Del { index } => names.remove(&index),
+                    };
+                }
+            }
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                NAMES.write().unwrap().clear();
+                if let Err(e) = monitor.dump() {
+                    stop(e);
+                    return;
+                }
+            }
+            Err(e) => {
+                stop(e);
+                return;
+            }
+        }
+    });
+    Ok(())
+}
+
+fn stop(e: io::Error) {
+    eprintln!("ERROR: rtnetlink: {}", e);
+    WATCHING.store(false, Ordering::Release);
+    NAMES.write().unwrap().clear();
+}
@@ -1,10 +1,10 @@
 use std::marker::PhantomData;
 use std::ops::Deref;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::thread::panicking;
+use std::thread::yield_now;
 
-/// A wait-free read lock
+/// A wait-free read lock, can be read by many threads at the same time
 pub struct LeftRightLock<T> {
     left: T,
     right: Option<T>,
@@ -27,30 +27,46 @@ impl<T> LeftRightLock<T> {
 }
 
 pub struct Reader<T>(Arc<LeftRightLock<T>>, PhantomData<*const ()>);
-unsafe impl<T: Send> Send for Reader<T> {}
+unsafe impl<T: Send + Sync> Send for Reader<T> {}
+
+impl<T> Clone for Reader<T> {
+    fn clone(&self) -> Self {
+        Reader(self.0.clone(), PhantomData)
+    }
+}
 
 pub struct Setter<T>(Arc<LeftRightLock<T>>, PhantomData<*const ()>);
-unsafe impl<T: Send> Send for Setter<T> {}
+unsafe impl<T: Send + Sync> Send for Setter<T> {}
 
 impl<T> Reader<T> {
     pub fn read(&self) -> ReadGuard<T> {
-        let side = unsafe { self.0.state.set_read() };
+        self.0.read()
+    }
+}
+
+impl<T> LeftRightLock<T> {
+    fn read(&self) -> ReadGuard<T> {
+        let side = unsafe { self.state.set_read() };
 
         ReadGuard {
             value: if side {
-                &self.0.left
+                &self.left
             } else {
-                self.0
-                    .right
+                self.right
                     .as_ref()
                     .expect("unreachable LeftRightLock state")
             },
-            state: &self.0.state,
+            side,
+            state: &self.state,
         }
     }
 }
 
 impl<T> Setter<T> {
+    /// The value that was set last
+    pub fn read(&self) -> ReadGuard<T> {
+        self.0.read()
+    }
     pub fn set(&self, value: T) {
         unsafe {
             self.0.state.swap_side(|current_side_a| {
@@ -67,6 +83,7 @@ impl<T> Setter<T> {
 
 pub struct ReadGuard<'a, T> {
     value: &'a T,
+    side: bool,
     state: &'a State,
 }
 
@@ -79,49 +96,65 @@ impl<'a, T> Deref for ReadGuard<'a, T> {
 
 impl<'a, T> Drop for ReadGuard<'a, T> {
     fn drop(&mut self) {
-        if !panicking() {
-            unsafe { self.state.unset_read() }
-        }
+        // also while panicking, the setter would wait for this reader forever
+        unsafe { self.state.unset_read(self.side) }
     }
 }
 
+/// The current side, and the number of readers of each side
 struct State {
-    state: AtomicUsize,
+    side: AtomicBool,
+    left_readers: AtomicUsize,
+    right_readers: AtomicUsize,
 }
 
 impl State {
     fn new(side: bool) -> Self {
         State {
-            state: AtomicUsize::new(if side { 0b000 } else { 0b100 }),
+            side: AtomicBool::new(side),
+            left_readers: AtomicUsize::new(0),
+            right_readers: AtomicUsize::new(0),
+        }
+    }
+    fn readers(&self, side: bool) -> &AtomicUsize {
+        if side {
+            &self.left_readers
+        } else {
+            &self.right_readers
         }
     }
     unsafe fn set_read(&self) -> bool {
-        match self.state.fetch_add(1, Ordering::AcqRel) {
-            0b000 => true,
-            0b100 => false,
-            0b001 | 0b101 => panic!("LeftRightLock can only have one reader"),
-            _ => unreachable!(),
+        loop {
+            let side = self.side.load(Ordering::SeqCst);
+            self.readers(side).fetch_add(1, Ordering::SeqCst);
+            // The side may be swapped before the reader is counted, try again
+            if self.side.load(Ordering::SeqCst) == side {
+                return side;
+            }
+            self.readers(side).fetch_sub(1, Ordering::SeqCst);
         }
     }
-    unsafe fn unset_read(&self) {
-        match self.state.fetch_sub(1, Ordering::AcqRel) {
-            0b001 | 0b101 => (),
-            _ => unreachable!(),
+    unsafe fn unset_read(&self, side: bool) {
+        self.readers(side).fetch_sub(1, Ordering::SeqCst);
+    }
+    fn wait_readers(&self, side: bool) {
+        while self.readers(side).load(Ordering::SeqCst) != 0 {
+            yield_now();
         }
     }
     unsafe fn swap_side<F: FnOnce(bool)>(&self, f: F) {
-        // Apply the mask to get a "not reading" value
-        let current_side = self.state.load(Ordering::Acquire) & 0b100;
-        let next_side = current_side ^ 0b100;
+        // Only the setter swaps sides
+        let current_side = self.side.load(Ordering::SeqCst);
+        let next_side = !current_side;
 
-        f(current_side == 0);
+        // Readers that are retrying in `set_read` may still hold the next side
+        self.wait_readers(next_side);
+        f(current_side);
 
-        // swap to next side when current side is not reading
-        while self
-            .state
-            .compare_and_swap(current_side, next_side, Ordering::AcqRel)
-            != current_side
-        {}
+        // New readers go to the next side,
+        // the current side can be written again when old readers are gone
+        self.side.store(next_side, Ordering::SeqCst);
+        self.wait_readers(current_side);
     }
 }
 
@@ -138,10 +171,50 @@ mod test {
         assert_eq!(*r.read(), 2);
     }
     #[test]
-    #[should_panic(expected = "LeftRightLock can only have one reader")]
     fn two_reader() {
-        let (r, _s) = LeftRightLock::new(0);
-        let _a = r.read();
-        r.read();
+        let (r, s) = LeftRightLock::new(0);
+        let r2 = r.clone();
+        let a = r.read();
+        assert_eq!(*r2.read(), 0);
+        drop(a);
+        s.set(1);
+        assert_eq!(*r2.read(), 1);
+    }
+    #[test]
+    fn threads() {
+        let (r, s) = LeftRightLock::new(0);
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let r = r.clone();
+                std::thread::spawn(move || {
+                    let mut last = 0;
+                    for _ in 0..10000 {
+                        let v = *r.read();
+                        assert!(v >= last);
+                        last = v;
+                    }
+                })
+            })
+            .collect();
+        for i in 1..=1000 {
+            s.set(i);
+        }
+        for t in readers {
+            t.join().unwrap();
+        }
+        assert_eq!(*r.read(), 1000);
+    }
+    #[test]
+    fn panicking_reader() {
+        let (r, s) = LeftRightLock::new(0);
+        let r2 = r.clone();
+        let reader = std::thread::spawn(move || {
+            let _guard = r2.read();
+            panic!("reader panicked");
+        });
+        assert!(reader.join().is_err());
+        s.set(1);
+        s.set(2);
+        assert_eq!(*r.read(), 2);
     }
 }
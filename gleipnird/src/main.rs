@@ -6,40 +6,246 @@
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
 use std::hash::{Hash, Hasher};
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+use std::process::exit;
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 use crossbeam_channel;
-use gleipnir_interface::{Device, PackageReport, Proto};
+use ctrlc;
+use gleipnir_interface::{
+    script_arg, Ancestor, ConnectionPrompt, Device, ExeAlert, Owner, OwnerPolicy, PackageReport,
+    PacketInfo, Proto, RuleTarget,
+};
 use lru_time_cache::LruCache;
 use nfq;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::unistd::Uid;
-use pnet::packet::{
-    ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, ipv6::Ipv6Packet, tcp::TcpPacket, udp::UdpPacket,
-};
+use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, udp::UdpPacket};
 
 #[macro_use]
 mod utils;
 mod config;
+mod dns;
+mod ebpf;
+mod integrity;
+mod interfaces;
 mod lrlock;
 mod netfilter;
 mod netlink;
+mod packet;
 mod polkit;
 mod proc;
+mod prompt;
 pub mod rpc_server;
 mod rules;
+mod sockets;
 
 use rules::IndexedRules;
 
 const QUEUE_ID: u16 = 786;
+/// Incoming DNS responses, they are copied whole, see `dns`
+const DNS_QUEUE_ID: u16 = QUEUE_ID - 1;
+/// IPv4 + TCP is at most (20 + 40 optional) + (20 + 40 optional) = 120,
+/// the rest is for IPv6 extension headers
+const COPY_RANGE: u16 = 256;
+/// A socket may be closed and its 5-tuple taken by another process, without anyone knowing
+const SOCKET_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Owners of cached sockets are checked at most this often, see `is_alive`
+const ALIVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DNS_COPY_RANGE: u16 = 0xffff;
+
+/// Transport protocols that are not supported
+const UNSUPPORTED_VERDICT: nfq::Verdict = nfq::Verdict::Accept;
+/// Extension headers longer than `COPY_RANGE`, there is no telling where it goes
+const TRUNCATED_VERDICT: nfq::Verdict = nfq::Verdict::Drop;
+/// Headers are broken, the kernel would drop it anyway
+const MALFORMED_VERDICT: nfq::Verdict = nfq::Verdict::Drop;
+/// Fragments other than the first one, see `packet`
+const FRAGMENT_VERDICT: nfq::Verdict = nfq::Verdict::Accept;
+/// How often a worker without packets checks for answers of its prompts, and for hashes
+const PROMPT_POLL_INTERVAL_MS: i32 = 100;
+/// Packets held by a worker until executables are hashed, the rest are dropped and
+/// retransmitted later
+const MAX_HASHING: usize = 256;
+
+#[derive(Clone)]
+struct Socket {
+    cookie: u64,
+    /// `None` for sockets owned by the kernel
+    uid: Option<u32>,
+    gid: Option<u32>,
+    /// The owner the rules are checked against, see `choose_owner`
+    proc: proc::Process,
+    /// Other processes with the socket open, or with the other sockets if `ambiguous`
+    others: Vec<proc::Process>,
+    /// `SO_REUSEPORT` sockets of different programs, the kernel picks one per flow
+    ambiguous: bool,
+    /// The generation of the rules `proc` was chosen by
+    chosen_by: Option<u32>,
+}
+
+impl Socket {
+    fn kernel() -> Self {
+        Socket {
+            cookie: 0,
+            uid: None,
+            gid: None,
+            proc: proc::Process::kernel(),
+            others: Vec::new(),
+            ambiguous: false,
+            chosen_by: None,
+        }
+    }
+
+    /// Puts the owner of a shared socket the rules are checked against in `proc`,
+    /// see `OwnerPolicy`, the strictest one if the socket is `ambiguous`.
+    /// Returns true if it's chosen now, which is once per flow and rules.
+    fn choose_owner(
+        &mut self,
+        rules: &IndexedRules,
+        integrity: &integrity::Integrity,
+        packet: PacketInfo,
+    ) -> bool {
+        if self.others.is_empty() || self.chosen_by == Some(rules.generation()) {
+            return false;
+        }
+        let policy = if self.ambiguous {
+            OwnerPolicy::All
+        } else {
+            rules.owner_policy()
+        };
+        let mut owners = mem::take(&mut self.others);
+        owners.push(self.proc.clone());
+        proc::sort_owners(&mut owners, policy);
+        if policy == OwnerPolicy::All {
+            let strictest = {
+                let facts: Vec<_> = owners
+                    .iter()
+                    .map(|proc| Facts::new(rules, integrity, proc))
+                    .collect();
+                let packets: Vec<_> = owners
+                    .iter()
+                    .zip(&facts)
+                    .map(|(proc, facts)| PacketInfo {
+                        // the sockets may belong to different users
+                        uid: if self.ambiguous {
+                            Some(proc.uid)
+                        } else {
+                            packet.uid
+                        },
+                        gid: Some(proc.gid),
+                        ..facts.packet(packet, proc)
+                    })
+                    .collect();
+                rules.strictest(&packets)
+            };
+            owners.swap(0, strictest);
+        }
+        self.proc = owners.remove(0);
+        if self.ambiguous {
+            self.uid = Some(self.proc.uid);
+        }
+        self.gid = Some(self.proc.gid);
+        self.others = owners;
+        self.chosen_by = Some(rules.generation());
+        true
+    }
+
+    /// Every owner if `ambiguous`, see `PackageReport::owners`
+    fn owners(&self) -> Vec<Owner> {
+        if !self.ambiguous {
+            return Vec::new();
+        }
+        std::iter::once(&self.proc)
+            .chain(&self.others)
+            .map(|proc| Owner {
+                pid: proc.pid,
+                exe: proc.exe.clone(),
+            })
+            .collect()
+    }
+}
+
+/// What the rules need to know about a process, besides `proc::Process` itself
+struct Facts<'a> {
+    args: &'a [String],
+    ancestors: Vec<Ancestor>,
+    exe_hash: Option<String>,
+}
+
+impl<'a> Facts<'a> {
+    fn new(
+        rules: &IndexedRules,
+        integrity: &integrity::Integrity,
+        proc: &'a proc::Process,
+    ) -> Self {
+        Facts {
+            args: rules.script_args(&proc.exe, &proc.cmdline),
+            ancestors: if rules.needs_ancestors() {
+                proc::ancestors(proc)
+            } else {
+                Vec::new()
+            },
+            exe_hash: if rules.needs_hash(&proc.exe) {
+                // not pending, see `Hold::Hash`
+                match integrity.hash(proc.id(), &proc.exe) {
+                    integrity::Hash::Done(hash) => hash,
+                    integrity::Hash::Pending => None,
+                }
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Fills in the process, `gid` is left to the caller
+    fn packet<'b>(&'b self, packet: PacketInfo<'b>, proc: &'b proc::Process) -> PacketInfo<'b> {
+        PacketInfo {
+            exe: &proc.exe,
+            cgroup: &proc.cgroup,
+            app_id: proc.app_id.as_deref(),
+            args: self.args,
+            ancestors: &self.ancestors,
+            exe_hash: self.exe_hash.as_deref(),
+            ..packet
+        }
+    }
+}
 
 struct State {
     diag: netlink::SockDiag,
+    /// `None` in `SockDiagMode::Query`
+    sockets: Option<Arc<sockets::SocketTable>>,
     rules: lrlock::Reader<IndexedRules>,
     pkt_logs: crossbeam_channel::Sender<PackageReport>,
-    cache: LruCache<u64, proc::Process>,
+    /// Flow hash to socket
+    cache: LruCache<u64, Socket>,
+    /// Socket cookie to owners, cookies are never reused
+    owners: LruCache<u64, Vec<proc::Process>>,
+    /// Processes found alive recently
+    alive: LruCache<proc::ProcId, ()>,
+    verdicts: Option<Arc<ebpf::Verdicts>>,
+    /// The netfilter rules are registered, marks are handled by the kernel
+    registered: bool,
+    /// Cache verdicts in conntrack marks
+    connmark: bool,
+    pending: prompt::Pending,
+    dns: Arc<dns::Cache>,
+    integrity: Arc<integrity::Integrity>,
+}
+
+fn flow_hash(device: Device, protocol: Proto, src: SocketAddr, dst: SocketAddr) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (device, protocol, src, dst).hash(&mut hasher);
+    hasher.finish()
 }
 
 impl State {
@@ -49,20 +255,18 @@ impl State {
         protocol: Proto,
         src: SocketAddr,
         dst: SocketAddr,
-    ) -> Result<proc::Process, io::Error> {
-        let mut hasher = DefaultHasher::new();
-        (device, protocol, src, dst).hash(&mut hasher);
-        let lru_index = hasher.finish();
-
-        self.cache
-            .get(&lru_index)
-            .cloned()
-            .map(Ok)
-            .unwrap_or_else(|| {
+    ) -> Result<Socket, io::Error> {
+        let lru_index = flow_hash(device, protocol, src, dst);
+
+        match self.cache.get(&lru_index) {
+            // the PID may have been reused since
+            Some(socket) if is_alive(&mut self.alive, socket.proc.id()) => Ok(socket.clone()),
+            _ => {
                 let result = self.query_process(device, protocol, src, dst)?;
                 self.cache.insert(lru_index, result.clone());
                 Ok(result)
-            })
+            }
+        }
     }
     fn query_process(
         &mut self,
@@ -70,68 +274,114 @@ impl State {
         protocol: Proto,
         src: SocketAddr,
         dst: SocketAddr,
-    ) -> Result<proc::Process, io::Error> {
-        let mut possible_sockets: [Option<(_, _)>; 3] = [None; 3];
-
-        match protocol {
-            Proto::Tcp => {
-                if device.is_input() {
-                    // for INPUT, dst is loacal address, src is remote address
-                    possible_sockets[0] = Some((dst, src));
-                } else {
-                    possible_sockets[0] = Some((src, dst));
-                }
-            }
-            Proto::Udp | Proto::UdpLite => {
-                // for UDP listener, the remote address is unspecified
-                let unspecified_addr = if src.is_ipv4() {
-                    Ipv4Addr::UNSPECIFIED.into()
-                } else {
-                    Ipv6Addr::UNSPECIFIED.into()
-                };
-                let unspecified_socket = SocketAddr::new(unspecified_addr, 0);
-                if device.is_input() {
-                    possible_sockets[0] = Some((dst, src));
-                    possible_sockets[1] = Some((dst, unspecified_socket));
-                    possible_sockets[2] = Some((
-                        SocketAddr::new(unspecified_addr, dst.port()),
-                        unspecified_socket,
-                    ));
-                } else {
-                    possible_sockets[0] = Some((src, dst));
-                    possible_sockets[1] = Some((src, unspecified_socket));
-                    possible_sockets[2] = Some((
-                        SocketAddr::new(unspecified_addr, src.port()),
-                        unspecified_socket,
-                    ));
-                };
+    ) -> Result<Socket, io::Error> {
+        // for INPUT, dst is loacal address, src is remote address
+        let (local, remote) = if device.is_input() {
+            (dst, src)
+        } else {
+            (src, dst)
+        };
+        let mut diag_msgs = self.lookup(protocol, &sockets::candidates(protocol, local, remote))?;
+        // a SYN-ACK is sent by a request socket, on behalf of the listener
+        if diag_msgs.iter().all(sockets::is_request) {
+            match self.lookup(protocol, &sockets::listeners(local)) {
+                Ok(r) => diag_msgs = r,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
             }
         }
 
-        let mut diag_msg = None;
-        for &(local_address, remote_address) in possible_sockets
+        // created by the kernel, or left behind by a process
+        let (diag_msgs, unowned): (Vec<_>, Vec<_>) = diag_msgs
+            .into_iter()
+            .partition(|diag_msg| diag_msg.idiag_inode != 0);
+        let mut found: Vec<_> = diag_msgs
+            .into_iter()
+            .map(|diag_msg| (diag_msg, self.owners_of(&diag_msg)))
+            .filter(|(_, owners)| !owners.is_empty())
+            .collect();
+        // `SO_REUSEPORT` sockets of different programs, the kernel picks one per flow
+        let programs = |(diag_msg, owners): &(netlink::InetDiagMsg, Vec<proc::Process>)| {
+            let exes: BTreeSet<&str> = owners.iter().map(|proc| proc.exe.as_str()).collect();
+            (diag_msg.idiag_uid, exes)
+        };
+        let ambiguous = found
             .iter()
-            .take_while(|x| Option::is_some(x))
-            .map(|x| x.as_ref().unwrap())
-        {
-            match self.diag.query(protocol, local_address, remote_address) {
-                Ok(r) => diag_msg = Some(r),
-                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
-                Err(e) => return Err(e),
+            .any(|socket| programs(socket) != programs(&found[0]));
+        if found.is_empty() {
+            return match unowned.first() {
+                Some(diag_msg) => Ok(Socket {
+                    cookie: diag_msg.cookie(),
+                    proc: proc::Process::kernel_subsystem(sockets::kernel_subsystem(
+                        protocol, diag_msg,
+                    )),
+                    ..Socket::kernel()
+                }),
+                None => Err(io::ErrorKind::NotFound.into()),
             };
-            break;
         }
 
-        let diag_msg = match diag_msg {
-            Some(r) => r,
-            None => return Err(io::ErrorKind::NotFound.into()),
-        };
+        let mut found = found.into_iter();
+        let (diag_msg, mut owners) = found.next().expect("found nothing");
+        if ambiguous {
+            // the rules are checked against all of them, see `choose_owner`
+            for (_, others) in found {
+                for proc in others {
+                    if !owners.iter().any(|owner| owner.id() == proc.id()) {
+                        owners.push(proc);
+                    }
+                }
+            }
+        }
+        let proc = owners.remove(0);
+        Ok(Socket {
+            cookie: diag_msg.cookie(),
+            uid: Some(diag_msg.idiag_uid),
+            gid: Some(proc.gid),
+            proc,
+            others: owners,
+            ambiguous,
+            chosen_by: None,
+        })
+    }
+
+    fn lookup(
+        &mut self,
+        protocol: Proto,
+        candidates: &[(SocketAddr, SocketAddr)],
+    ) -> Result<Vec<netlink::InetDiagMsg>, io::Error> {
+        match &self.sockets {
+            Some(table) => table.find(&mut self.diag, protocol, candidates),
+            None => sockets::query(&mut self.diag, protocol, candidates),
+        }
+    }
 
-        proc::get_proc_by_inode(diag_msg.idiag_inode).ok_or_else(|| io::ErrorKind::NotFound.into())
+    /// Every process with the socket open
+    fn owners_of(&mut self, diag_msg: &netlink::InetDiagMsg) -> Vec<proc::Process> {
+        let cookie = diag_msg.cookie();
+        match self.owners.get(&cookie) {
+            Some(procs) if procs.iter().all(|proc| proc::is_alive(proc.id())) => procs.clone(),
+            _ => {
+                let procs = proc::get_procs_by_inode(diag_msg.idiag_inode, diag_msg.idiag_uid);
+                if !procs.is_empty() {
+                    self.owners.insert(cookie, procs.clone());
+                }
+                procs
+            }
+        }
     }
 }
 
-fn queue_callback(msg: &mut nfq::Message, state: &mut State) {
+/// Why a packet is held instead of getting a verdict
+enum Hold {
+    /// Until the user answers
+    Prompt(prompt::Request),
+    /// Until the executables of its owners are hashed, then it's checked again
+    Hash,
+}
+
+/// Returns why the packet must be held, if it must
+fn queue_callback(msg: &mut nfq::Message, state: &mut State) -> Option<Hold> {
     let device = if msg.get_indev() != 0 {
         Device::Input
     } else if msg.get_outdev() != 0 {
@@ -139,43 +389,63 @@ fn queue_callback(msg: &mut nfq::Message, state: &mut State) {
     } else {
         unreachable!("package is from neither INPUT nor OUTPUT");
     };
+    let interface = interfaces::name(if device == Device::Input {
+        msg.get_indev()
+    } else {
+        msg.get_outdev()
+    });
 
     let payload = msg.get_payload();
-    let (saddr, daddr, protocol, ip_payload) = match payload[0] >> 4 {
-        4 => {
-            let pkt = Ipv4Packet::new(payload).expect("Ipv4Packet");
-            let src: IpAddr = pkt.get_source().into();
-            let dst: IpAddr = pkt.get_destination().into();
-            (
-                src,
-                dst,
-                pkt.get_next_level_protocol(),
-                &payload[Ipv4Packet::minimum_packet_size()..],
-            )
-        }
-        6 => {
-            let pkt = Ipv6Packet::new(payload).expect("Ipv6Packet");
-            let src: IpAddr = pkt.get_source().into();
-            let dst: IpAddr = pkt.get_destination().into();
-            (
-                src,
-                dst,
-                pkt.get_next_header(),
-                &payload[Ipv6Packet::minimum_packet_size()..],
-            )
-        }
-        _ => unreachable!("package is neither IPv4 nor IPv6"),
+    // only the beginning of the packet is copied to userspace, see `COPY_RANGE`
+    let copied_all = payload.len() >= msg.get_original_len();
+    let pkt = match packet::parse(payload) {
+        Ok(pkt) => pkt,
+        Err(packet::ParseError::Truncated) if !copied_all => {
+            // too many extension headers, we can't tell
+            msg.set_verdict(TRUNCATED_VERDICT);
+            return None;
+        }
+        Err(_) => {
+            msg.set_verdict(MALFORMED_VERDICT);
+            return None;
+        }
+    };
+    let (saddr, daddr, protocol) = (pkt.src, pkt.dst, pkt.protocol);
+    let ip_payload = match pkt.transport {
+        Some(payload) => payload,
+        None => {
+            msg.set_verdict(FRAGMENT_VERDICT);
+            return None;
+        }
     };
 
+    let mut icmp = None;
+    let mut kernel_owned = false;
+    // after the TCP or UDP header
+    let mut transport_payload = None;
     let (protocol, sport, dport) = match protocol {
         IpNextHeaderProtocols::Tcp => {
-            let pkt = TcpPacket::new(ip_payload).expect("TcpPacket");
+            let pkt = match TcpPacket::new(ip_payload) {
+                Some(pkt) => pkt,
+                None => {
+                    msg.set_verdict(MALFORMED_VERDICT);
+                    return None;
+                }
+            };
             let (sport, dport) = (pkt.get_source(), pkt.get_destination());
+            transport_payload = ip_payload.get(pkt.get_data_offset() as usize * 4..);
             (Proto::Tcp, sport, dport)
         }
         IpNextHeaderProtocols::Udp | IpNextHeaderProtocols::UdpLite => {
-            let pkt = UdpPacket::new(ip_payload).expect("UdpPacket");
+            let pkt = match UdpPacket::new(ip_payload) {
+                Some(pkt) => pkt,
+                None => {
+                    msg.set_verdict(MALFORMED_VERDICT);
+                    return None;
+                }
+            };
             let (sport, dport) = (pkt.get_source(), pkt.get_destination());
+            transport_payload = ip_payload.get(UdpPacket::minimum_packet_size()..);
             let p = if protocol == IpNextHeaderProtocols::Udp {
                 Proto::Udp
             } else {
@@ -183,89 +453,535 @@ fn queue_callback(msg: &mut nfq::Message, state: &mut State) {
             };
             (p, sport, dport)
         }
+        IpNextHeaderProtocols::Icmp | IpNextHeaderProtocols::Icmpv6 => {
+            let p = if protocol == IpNextHeaderProtocols::Icmp {
+                Proto::Icmp
+            } else {
+                Proto::Icmpv6
+            };
+            let (icmp_type, icmp_code) = match ip_payload {
+                [t, c, ..] => (*t, *c),
+                _ => {
+                    msg.set_verdict(MALFORMED_VERDICT);
+                    return None;
+                }
+            };
+            icmp = Some((icmp_type, icmp_code));
+            // the local port is the identifier, the remote port is always 0
+            match ping_socket_id(p, device, icmp_type, ip_payload) {
+                Some(id) if device.is_input() => (p, 0, id),
+                Some(id) => (p, id, 0),
+                None => {
+                    kernel_owned = true;
+                    (p, 0, 0)
+                }
+            }
+        }
         _ => {
             // ignore other protocol
-            msg.set_verdict(nfq::Verdict::Accept);
-            return;
+            msg.set_verdict(UNSUPPORTED_VERDICT);
+            return None;
         }
     };
     let (src, dst) = (SocketAddr::new(saddr, sport), SocketAddr::new(daddr, dport));
 
-    let proc = match state.query_process_cached(device, protocol, src, dst) {
-        Ok(r) => r,
-        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
-            eprintln!("NOT FOUND: {:?},\t{},\t{},\t{}", device, protocol, src, dst);
-            msg.set_verdict(nfq::Verdict::Accept);
-            return;
+    let is_dns = (protocol == Proto::Tcp || protocol == Proto::Udp)
+        && (sport == dns::PORT || dport == dns::PORT);
+    // queries to and responses from a resolver, remembered once they are accepted, see `dns`
+    let dns_msg = match (device, transport_payload) {
+        (Device::Output, Some(payload)) if is_dns && dport == dns::PORT => {
+            match parse_dns(protocol, payload) {
+                Ok(query @ dns::Message::Query(_)) => Some(query),
+                _ => None,
+            }
+        }
+        (Device::Input, Some(payload)) if is_dns && sport == dns::PORT && copied_all => {
+            match parse_dns(protocol, payload) {
+                Ok(response @ dns::Message::Response(_)) => Some(response),
+                _ => None,
+            }
         }
+        // the ones that can't be parsed are just ordinary packets
+        _ => None,
+    };
+
+    let (rule_addr, local_addr) = if device.is_input() {
+        (src, dst)
+    } else {
+        (dst, src)
+    };
+
+    let socket = if kernel_owned {
+        Ok(Socket::kernel())
+    } else {
+        state.query_process_cached(device, protocol, src, dst)
+    };
+    let names = if state.rules.read().needs_names() {
+        state.dns.names(rule_addr.ip())
+    } else {
+        Vec::new()
+    };
+
+    let mut socket = match socket {
+        Ok(r) => r,
+        // echo from a raw socket, it's not visible to sock_diag
+        Err(ref e) if protocol.is_icmp() && e.kind() == io::ErrorKind::NotFound => Socket::kernel(),
         Err(e) => {
-            eprintln!(
-                "ERROR: {},\t{:?},\t{},\t{},\t{}",
-                e, device, protocol, src, dst
-            );
-            msg.set_verdict(nfq::Verdict::Accept);
-            return;
+            if e.kind() != io::ErrorKind::NotFound {
+                eprintln!(
+                    "ERROR: {},\t{:?},\t{},\t{},\t{}",
+                    e, device, protocol, src, dst
+                );
+            }
+            // not cached anywhere, the process may be found by the next packet,
+            // and there is nothing to ask about, `RuleTarget::Ask` falls back
+            let packet = PacketInfo {
+                device,
+                protocol,
+                addr: rule_addr,
+                local_addr,
+                icmp,
+                interface: interface.as_deref(),
+                exe: "",
+                uid: None,
+                gid: None,
+                cgroup: "",
+                app_id: None,
+                args: &[],
+                ancestors: &[],
+                exe_hash: None,
+                names: &names,
+            };
+            let (rule_id, target, accept) = state
+                .rules
+                .read()
+                .is_acceptable_unknown(&packet, payload.len());
+            set_verdict(msg, state, target, accept);
+            let log = PackageReport {
+                device,
+                protocol,
+                addr: rule_addr,
+                local_addr,
+                len: msg.get_original_len(),
+                exe: String::new(),
+                uid: None,
+                app_id: None,
+                script: None,
+                matched_ancestor: None,
+                interface,
+                dropped: !accept,
+                matched_rule: rule_id,
+                unknown_process: true,
+                ambiguous: false,
+                owners: Vec::new(),
+            };
+            state.pkt_logs.try_send(log).expect("logs service dead");
+            return None;
         }
     };
 
-    let rule_addr = if device.is_input() { src } else { dst };
     let rules = state.rules.read();
-    let (rule_id, accept) =
-        rules.is_acceptable(device, protocol, rule_addr, payload.len(), &proc.exe);
+    let packet = PacketInfo {
+        device,
+        protocol,
+        addr: rule_addr,
+        local_addr,
+        icmp,
+        interface: interface.as_deref(),
+        exe: "",
+        uid: socket.uid,
+        gid: None,
+        cgroup: "",
+        app_id: None,
+        args: &[],
+        ancestors: &[],
+        exe_hash: None,
+        names: &names,
+    };
+    // they are hashed by another thread
+    let owners = if socket.ambiguous || rules.owner_policy() == OwnerPolicy::All {
+        &socket.others[..]
+    } else {
+        &[]
+    };
+    let hashing = std::iter::once(&socket.proc)
+        .chain(owners)
+        .filter(|proc| rules.needs_hash(&proc.exe))
+        .any(|proc| match state.integrity.hash(proc.id(), &proc.exe) {
+            integrity::Hash::Pending => true,
+            integrity::Hash::Done(_) => false,
+        });
+    if hashing {
+        return Some(Hold::Hash);
+    }
+    if socket.choose_owner(&rules, &state.integrity, packet) {
+        // decided once per flow
+        let flow = flow_hash(device, protocol, src, dst);
+        state.cache.insert(flow, socket.clone());
+    }
+    let facts = Facts::new(&rules, &state.integrity, &socket.proc);
+    let script = script_arg(&socket.proc.exe, facts.args).map(str::to_owned);
+    let packet = PacketInfo {
+        gid: socket.gid,
+        ..facts.packet(packet, &socket.proc)
+    };
+    let (rule_id, mut target, mut accept) = rules.is_acceptable(&packet, payload.len());
+    let matched_ancestor = rules.matched_ancestor(rule_id, &packet).cloned();
+    if let Some(expected) = rules.hash_mismatch(rule_id, &packet) {
+        let alert = ExeAlert::HashMismatch {
+            pid: socket.proc.pid,
+            exe: socket.proc.exe.clone(),
+            rule: rule_id.unwrap(),
+            expected: expected.to_owned(),
+            actual: facts.exe_hash.clone(),
+        };
+        state.integrity.alert(socket.proc.id(), alert);
+    }
+    if socket.proc.exe_deleted {
+        let alert = ExeAlert::Deleted {
+            pid: socket.proc.pid,
+            exe: socket.proc.exe.clone(),
+        };
+        state.integrity.alert(socket.proc.id(), alert);
+    }
+
+    if target == RuleTarget::Ask {
+        let flow = flow_hash(device, protocol, src, dst);
+        // also the held packets, they come back once answered, see `prompt`
+        match state.pending.answered(flow) {
+            Some(answer) => {
+                target = if answer {
+                    RuleTarget::Accept
+                } else {
+                    RuleTarget::Drop
+                };
+                accept = answer;
+            }
+            None => {
+                return Some(Hold::Prompt(prompt::Request {
+                    flow,
+                    prompt: ConnectionPrompt {
+                        id: 0,
+                        pid: socket.proc.pid,
+                        exe: socket.proc.exe.clone(),
+                        device,
+                        protocol,
+                        addr: rule_addr,
+                    },
+                    timeout: rules.ask_timeout(),
+                    fallback: accept,
+                }))
+            }
+        }
+    }
+
+    set_verdict(msg, state, target, accept);
+    if let Some(dns_msg) = dns_msg {
+        if accept && socket.cookie != 0 {
+            state.dns.accepted(socket.cookie, rule_addr, dns_msg);
+        }
+    }
+
+    match target {
+        // ICMP errors belong to the connection they are related to, don't touch its mark,
+        // and there is no socket cookie for raw sockets
+        _ if socket.cookie == 0 => (),
+        // queries and responses must keep coming to userspace, see `dns`
+        _ if is_dns => {
+            if state.verdicts.is_some() {
+                msg.set_nfmark(msg.get_nfmark() | netfilter::MARK_USERSPACE);
+            }
+        }
+        RuleTarget::Accept | RuleTarget::Drop | RuleTarget::Reject if state.connmark => {
+            let verdict = match target {
+                RuleTarget::Accept => netfilter::MARK_ACCEPT,
+                RuleTarget::Drop => netfilter::MARK_DROP,
+                _ => netfilter::MARK_REJECT,
+            };
+            // dropped or rejected by the kernel after the mark is saved to conntrack
+            msg.set_verdict(nfq::Verdict::Accept);
+            let mark = msg.get_nfmark() & !netfilter::MARK_MASK;
+            msg.set_nfmark(mark | netfilter::verdict_mark(verdict, rules.generation()));
+        }
+        RuleTarget::Accept | RuleTarget::Drop => {
+            if let Some(verdicts) = &state.verdicts {
+                let flow = ebpf::Flow::new(&packet);
+                let mark = msg.get_nfmark() & !netfilter::MARK_MASK;
+                match verdicts.set(socket.cookie, flow, accept) {
+                    // not queued anymore
+                    Ok(()) => msg.set_nfmark(mark | verdicts.mark()),
+                    // the map is full, the connection keeps coming to userspace
+                    Err(e) => {
+                        if e.raw_os_error() != Some(libc::E2BIG) {
+                            dbg!(e);
+                        }
+                        msg.set_nfmark(mark | netfilter::MARK_USERSPACE);
+                    }
+                }
+            }
+        }
+        // eBPF programs can only drop, and every packet must go through the bucket
+        RuleTarget::Reject | RuleTarget::RateLimit(_) => {
+            if let Some(verdicts) = &state.verdicts {
+                verdicts.remove(socket.cookie);
+                msg.set_nfmark(msg.get_nfmark() | netfilter::MARK_USERSPACE);
+            }
+        }
+        RuleTarget::Ask => unreachable!("replaced by the answer"),
+    }
 
+    let owners = socket.owners();
     let log = PackageReport {
         device,
         protocol,
         addr: rule_addr,
+        local_addr,
         len: msg.get_original_len(),
-        exe: proc.exe,
+        exe: socket.proc.exe,
+        uid: socket.uid,
+        app_id: socket.proc.app_id,
+        script,
+        matched_ancestor,
+        interface,
         dropped: !accept,
         matched_rule: rule_id,
+        unknown_process: false,
+        ambiguous: socket.ambiguous,
+        owners,
     };
 
-    if accept {
+    state.pkt_logs.try_send(log).expect("logs service dead");
+    None
+}
+
+/// `proc::is_alive` reads `/proc` without the proc connector, it's not done for every packet
+fn is_alive(alive: &mut LruCache<proc::ProcId, ()>, id: proc::ProcId) -> bool {
+    if alive.get(&id).is_some() {
+        return true;
+    }
+    let is_alive = proc::is_alive(id);
+    if is_alive {
+        alive.insert(id, ());
+    }
+    is_alive
+}
+
+fn parse_dns(protocol: Proto, payload: &[u8]) -> Result<dns::Message, dns::ParseError> {
+    match protocol {
+        Proto::Tcp => dns::parse_tcp(payload),
+        _ => dns::parse(payload),
+    }
+}
+
+fn set_verdict(msg: &mut nfq::Message, state: &State, target: RuleTarget, accept: bool) {
+    if target == RuleTarget::Reject && state.registered {
+        // rejected by the kernel after the verdict, see `netfilter`
+        msg.set_verdict(nfq::Verdict::Accept);
+        msg.set_nfmark(msg.get_nfmark() | netfilter::MARK_REJECT);
+    } else if accept {
         msg.set_verdict(nfq::Verdict::Accept);
     } else {
         msg.set_verdict(nfq::Verdict::Drop);
     }
+}
 
-    state.pkt_logs.try_send(log).expect("logs service dead");
+/// The identifier of an echo request sent by, or an echo reply sent to, a ping socket
+fn ping_socket_id(protocol: Proto, device: Device, icmp_type: u8, payload: &[u8]) -> Option<u16> {
+    const ICMP_ECHO_REQUEST: u8 = 8;
+    const ICMP_ECHO_REPLY: u8 = 0;
+    const ICMPV6_ECHO_REQUEST: u8 = 128;
+    const ICMPV6_ECHO_REPLY: u8 = 129;
+
+    let expected_type = match (protocol, device) {
+        (Proto::Icmp, Device::Output) => ICMP_ECHO_REQUEST,
+        (Proto::Icmp, Device::Input) => ICMP_ECHO_REPLY,
+        (Proto::Icmpv6, Device::Output) => ICMPV6_ECHO_REQUEST,
+        (Proto::Icmpv6, Device::Input) => ICMPV6_ECHO_REPLY,
+        _ => unreachable!("not ICMP"),
+    };
+    match payload {
+        // type, code, checksum, identifier, sequence number
+        [t, _, _, _, id0, id1, _, _, ..] if *t == expected_type => {
+            Some(u16::from_be_bytes([*id0, *id1]))
+        }
+        _ => None,
+    }
 }
 
 // TODO: expect messages
 fn main() {
+    let options = config::Options::from_args().expect("Invalid arguments");
+    if options.bench_sock_diag {
+        sockets::bench().expect("Failed to query sockets");
+        return;
+    }
     let rules = config::load_rules().expect("Failed to load rules");
 
-    let (rules_reader, rules_setter) =
-        lrlock::LeftRightLock::new(IndexedRules::from(rules.clone()));
+    if let Err(e) = proc::watch() {
+        eprintln!(
+            "WARNING: no process events, /proc is scanned on every miss: {}",
+            e
+        );
+    }
+    if let Err(e) = interfaces::watch() {
+        eprintln!(
+            "WARNING: no link events, interface names are looked up on every packet: {}",
+            e
+        );
+    }
+
+    let verdicts = match options.backend {
+        config::Backend::Nfqueue => None,
+        config::Backend::Ebpf => {
+            Some(ebpf::Verdicts::attach().expect("Failed to attach eBPF programs"))
+        }
+    };
+
+    let indexed_rules = IndexedRules::from(rules.clone());
+
+    let registration = if Uid::current().is_root() {
+        let mode = match &verdicts {
+            Some(verdicts) => netfilter::QueueMode::Uncached(verdicts.mark()),
+            None => netfilter::QueueMode::All,
+        };
+        let registration = match netfilter::register_nfqueue(
+            QUEUE_ID,
+            options.queue_balance,
+            DNS_QUEUE_ID,
+            mode,
+            indexed_rules.generation(),
+        ) {
+            Ok(registration) => Arc::new(registration),
+            Err(e) => {
+                eprintln!("ERROR: failed to register nfqueue: {}", e);
+                exit(1);
+            }
+        };
+        let nfqueue = registration.clone();
+        let verdicts = verdicts.clone();
+        ctrlc::set_handler(move || {
+            if let Err(e) = nfqueue.unregister() {
+                eprintln!("ERROR: failed to unregister nfqueue: {}", e);
+            }
+            if let Some(verdicts) = &verdicts {
+                verdicts.detach();
+            }
+            exit(0);
+        })
+        .expect("Error setting Ctrl-C handler");
+        Some(registration)
+    } else {
+        None
+    };
+
+    let (rules_reader, rules_setter) = lrlock::LeftRightLock::new(indexed_rules);
     let (sender, receiver) = crossbeam_channel::unbounded();
-    let mut state = State {
-        diag: netlink::SockDiag::new().expect(""),
-        rules: rules_reader,
-        pkt_logs: sender,
-        cache: LruCache::with_capacity(2048),
+    let connmark = verdicts.is_none() && registration.is_some();
+    let (prompter, prompts) = prompt::Prompter::new();
+    let prompter = Arc::new(prompter);
+    let dns = Arc::new(dns::Cache::new());
+    let (integrity, alerts) = integrity::Integrity::new();
+    let socket_table = match options.sock_diag {
+        config::SockDiagMode::Query => None,
+        config::SockDiagMode::Dump => {
+            Some(sockets::SocketTable::watch().expect("Failed to watch sockets"))
+        }
     };
-    let mut q = nfq::Queue::open().expect("");
+
+    // Every worker has its own queue, netlink socket and caches,
+    // packets of a flow always go to the same queue
+    let queues = (QUEUE_ID..QUEUE_ID + options.queue_balance).map(|num| (num, COPY_RANGE));
+    let workers: Vec<_> = queues
+        .chain(Some((DNS_QUEUE_ID, DNS_COPY_RANGE)))
+        .map(|(queue_num, copy_range)| {
+            let state = State {
+                diag: netlink::SockDiag::new().expect(""),
+                sockets: socket_table.clone(),
+                rules: rules_reader.clone(),
+                pkt_logs: sender.clone(),
+                cache: LruCache::with_expiry_duration_and_capacity(SOCKET_CACHE_TTL, 2048),
+                owners: LruCache::with_capacity(2048),
+                alive: LruCache::with_expiry_duration_and_capacity(ALIVE_CHECK_INTERVAL, 2048),
+                verdicts: verdicts.clone(),
+                registered: registration.is_some(),
+                connmark,
+                pending: prompt::Pending::new(prompter.clone()),
+                dns: dns.clone(),
+                integrity: integrity.clone(),
+            };
+            thread::spawn(move || worker(queue_num, copy_range, state))
+        })
+        .collect();
+    drop(sender);
 
     thread::spawn(|| {
-        if let Err(e) = rpc_server::run(rules, rules_setter, receiver) {
+        if let Err(e) = rpc_server::run(
+            rules,
+            rules_setter,
+            receiver,
+            verdicts,
+            registration,
+            prompter,
+            prompts,
+            alerts,
+        ) {
             dbg!(e);
             std::process::exit(1);
         }
     });
 
-    q.bind(QUEUE_ID).expect("");
-    // The max size of IPv4 + TCP is (20 + 40 optional) + (20 + 40 optional) = 120
-    q.set_copy_range(QUEUE_ID, 128).expect("");
-
-    if Uid::current().is_root() {
-        netfilter::register_nfqueue(QUEUE_ID);
+    for worker in workers {
+        if worker.join().is_err() {
+            exit(1);
+        }
     }
+}
+
+fn worker(queue_num: u16, copy_range: u16, mut state: State) {
+    let mut q = nfq::Queue::open().expect("");
+    q.bind(queue_num).expect("");
+    q.set_copy_range(queue_num, copy_range).expect("");
+    // held packets are released by answers, which don't come from the queue
+    q.set_nonblocking(true);
 
+    // waiting for `Hold::Hash`, checked again whenever a hash is done
+    let mut hashing: Vec<nfq::Message> = Vec::new();
+    let mut hashed = state.integrity.done();
     loop {
-        let mut msg = q.recv().expect("");
-        queue_callback(&mut msg, &mut state);
-        q.verdict(msg).expect("");
+        let mut msgs = Vec::new();
+        match q.recv() {
+            Ok(msg) => msgs.push(msg),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let mut fds = [PollFd::new(q.as_raw_fd(), PollFlags::POLLIN)];
+                match poll(&mut fds, PROMPT_POLL_INTERVAL_MS) {
+                    Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            Err(e) => panic!("{}", e),
+        }
+        if !hashing.is_empty() && state.integrity.done() != hashed {
+            hashed = state.integrity.done();
+            msgs.append(&mut hashing);
+        }
+        // answered, they get the verdict of the answer like later packets of the flow
+        msgs.extend(state.pending.resolve());
+        for mut msg in msgs {
+            match queue_callback(&mut msg, &mut state) {
+                Some(Hold::Prompt(request)) => {
+                    if let Some(mut msg) = state.pending.hold(request, msg) {
+                        msg.set_verdict(nfq::Verdict::Drop);
+                        q.verdict(msg).expect("");
+                    }
+                }
+                Some(Hold::Hash) if hashing.len() < MAX_HASHING => hashing.push(msg),
+                Some(Hold::Hash) => {
+                    msg.set_verdict(nfq::Verdict::Drop);
+                    q.verdict(msg).expect("");
+                }
+                None => q.verdict(msg).expect(""),
+            }
+        }
     }
 }
 
@@ -1,61 +1,435 @@
-use std::process::{exit, Command, Stdio};
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
 
-use ctrlc;
+use crate::dns;
 
-pub fn register_nfqueue(num: u16) {
-    if nft_exists() {
-    } else {
-        iptables_insert_nfqueue(num);
-        ctrlc::set_handler(move || {
-            iptables_remove_nfqueue(num);
-            exit(0);
-        })
-        .expect("Error setting Ctrl-C handler");
+const NFT_TABLE: &str = "gleipnir";
+
+// The highest 16 bits of packet and conntrack marks are reserved for gleipnird:
+//
+//   31: the connection always needs a verdict from userspace
+//   30: the connection is accepted
+//   29: the connection is dropped
+//   28: the connection is rejected, with a TCP RST or an ICMP port-unreachable
+//   27: the verdict of the socket is cached in eBPF (see `ebpf`)
+//   16..27: generation of rules that made the verdict, or the daemon run for eBPF
+pub const MARK_MASK: u32 = 0xffff_0000;
+pub const MARK_USERSPACE: u32 = 1 << 31;
+pub const MARK_ACCEPT: u32 = 1 << 30;
+pub const MARK_DROP: u32 = 1 << 29;
+pub const MARK_REJECT: u32 = 1 << 28;
+pub const MARK_EBPF: u32 = 1 << 27;
+pub const GENERATION_MASK: u32 = 0x7ff;
+const GENERATION_SHIFT: u32 = 16;
+
+/// Mark of a connection whose following packets can be handled by the kernel,
+/// it's only valid until the rules changed (see `Registration::set_generation`),
+/// or for `MARK_EBPF` until the daemon is restarted
+///
+/// `verdict` is one of `MARK_ACCEPT`, `MARK_DROP`, `MARK_REJECT` and `MARK_EBPF`
+pub fn verdict_mark(verdict: u32, generation: u32) -> u32 {
+    verdict | (generation & GENERATION_MASK) << GENERATION_SHIFT
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Backend {
+    Nftables,
+    Iptables,
+}
+
+impl Backend {
+    /// Prefer nftables, iptables is only used when `nft` is unavailable
+    pub fn detect() -> Self {
+        if nft_exists() {
+            Backend::Nftables
+        } else {
+            Backend::Iptables
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum QueueMode {
+    /// Every non-loopback packet
+    All,
+    /// Packets of connections without this mark, the kernel (eBPF) takes care of the rest
+    ///
+    /// New connections, the ones older than the daemon and the ones marked with
+    /// `MARK_USERSPACE` are all queued.
+    Uncached(u32),
+}
+
+pub struct Registration {
+    backend: Backend,
+    num: u16,
+    count: u16,
+    dns_num: u16,
+    mode: QueueMode,
+    generation: Mutex<u32>,
+}
+
+impl Registration {
+    /// Invalidate verdicts cached in conntrack marks, except the ones made by `generation`
+    pub fn set_generation(&self, generation: u32) -> Result<(), io::Error> {
+        let mut current = self.generation.lock().unwrap();
+        match self.backend {
+            Backend::Nftables => {
+                let mut script = format!("flush chain inet {} cached\n", NFT_TABLE);
+                for rule in nft_cached_rules(generation) {
+                    script.push_str(&format!("add rule inet {} cached {}\n", NFT_TABLE, rule));
+                }
+                nft(&script)?;
+            }
+            Backend::Iptables => {
+                // insert the new ones first, so there is no moment without them
+                iptables_insert(&iptables_cached_rules(generation));
+                iptables_remove(&iptables_cached_rules(*current));
+            }
+        }
+        *current = generation;
+        Ok(())
     }
+
+    pub fn unregister(&self) -> Result<(), io::Error> {
+        match self.backend {
+            Backend::Nftables => nft_remove_nfqueue()?,
+            Backend::Iptables => {
+                iptables_remove(&iptables_cached_rules(*self.generation.lock().unwrap()));
+                iptables_remove(&iptables_rules(
+                    self.num,
+                    self.count,
+                    self.dns_num,
+                    self.mode,
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Packets are balanced across queues `num..num + count` by flow,
+/// incoming DNS responses always go to queue `dns_num` (see `dns`)
+pub fn register_nfqueue(
+    num: u16,
+    count: u16,
+    dns_num: u16,
+    mode: QueueMode,
+    generation: u32,
+) -> Result<Registration, io::Error> {
+    assert!(count > 0, "At least one queue is needed");
+    let backend = Backend::detect();
+    match backend {
+        Backend::Nftables => nft_insert_nfqueue(num, count, dns_num, mode, generation)?,
+        Backend::Iptables => {
+            iptables_insert(&iptables_rules(num, count, dns_num, mode));
+            iptables_insert(&iptables_cached_rules(generation));
+        }
+    }
+    Ok(Registration {
+        backend,
+        num,
+        count,
+        dns_num,
+        mode,
+        generation: Mutex::new(generation),
+    })
 }
 
-// TODO: or just iptables-nft?
 fn nft_exists() -> bool {
-    false
+    Command::new("nft")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .arg("list")
+        .arg("tables")
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Run a script with `nft -f`, the whole script is applied atomically
+fn nft(script: &str) -> Result<(), io::Error> {
+    let mut child = Command::new("nft")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .arg("-f")
+        .arg("-")
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("nft stdin")
+        .write_all(script.as_bytes())?;
+    let status = child.wait()?;
+    if !status.success() {
+        let e = format!("nft failed, {}", status);
+        return Err(io::Error::new(io::ErrorKind::Other, e));
+    }
+    Ok(())
+}
+
+fn nft_cached_rules(generation: u32) -> Vec<String> {
+    [
+        (MARK_ACCEPT, "accept"),
+        (MARK_DROP, "drop"),
+        (MARK_REJECT, "jump do_reject"),
+    ]
+    .iter()
+    .map(|&(verdict, statement)| {
+        format!(
+            "ct mark & {:#x} == {:#x} {}",
+            MARK_MASK,
+            verdict_mark(verdict, generation),
+            statement
+        )
+    })
+    .collect()
 }
 
-fn iptables(v4: bool, output: bool, cmd: &str, queue_num: u16) -> Command {
+fn nft_queue(num: u16, count: u16) -> String {
+    if count == 1 {
+        format!("queue num {} bypass", num)
+    } else {
+        format!("queue num {}-{} fanout,bypass", num, num + count - 1)
+    }
+}
+
+fn nft_ruleset(num: u16, count: u16, dns_num: u16, mode: QueueMode, generation: u32) -> String {
+    let queue = match mode {
+        QueueMode::All => nft_queue(num, count),
+        QueueMode::Uncached(mark) => format!(
+            "ct mark & {mask:#x} != {mark:#x} {queue}",
+            queue = nft_queue(num, count),
+            mask = MARK_MASK,
+            mark = mark,
+        ),
+    };
+    // priority -150 is mangle, same as the iptables rules,
+    // marks set by userspace are saved to conntrack after the verdict, leaving the bits
+    // other tools use alone,
+    // DNS responses are never cached, so they can always be parsed
+    format!(
+        r#"table inet {table} {{
+    chain cached {{
+        {cached}
+    }}
+    chain input {{
+        type filter hook input priority -150; policy accept;
+        iifname "lo" accept
+        meta l4proto {{ tcp, udp }} th sport {dns_port} queue num {dns_num} bypass
+        jump cached
+        {queue}
+    }}
+    chain output {{
+        type filter hook output priority -150; policy accept;
+        oifname "lo" accept
+        jump cached
+        {queue}
+    }}
+    chain do_reject {{
+        meta l4proto tcp reject with tcp reset
+        reject with icmpx type port-unreachable
+    }}
+    chain save_mark {{
+        meta mark & {mask:#x} != 0 ct mark set ct mark & {others:#x} | meta mark & {mask:#x}
+        meta mark & {drop:#x} == {drop:#x} drop
+        meta mark & {reject:#x} == {reject:#x} jump do_reject
+    }}
+    chain input_mark {{
+        type filter hook input priority 0; policy accept;
+        jump save_mark
+    }}
+    chain output_mark {{
+        type filter hook output priority 0; policy accept;
+        jump save_mark
+    }}
+}}
+"#,
+        table = NFT_TABLE,
+        cached = nft_cached_rules(generation).join("\n        "),
+        queue = queue,
+        dns_port = dns::PORT,
+        dns_num = dns_num,
+        mask = MARK_MASK,
+        others = !MARK_MASK,
+        drop = MARK_DROP,
+        reject = MARK_REJECT,
+    )
+}
+
+fn nft_insert_nfqueue(
+    num: u16,
+    count: u16,
+    dns_num: u16,
+    mode: QueueMode,
+    generation: u32,
+) -> Result<(), io::Error> {
+    // `add` + `delete` makes sure a table left by a crashed daemon is replaced
+    let script = format!(
+        "add table inet {table}\ndelete table inet {table}\n{ruleset}",
+        table = NFT_TABLE,
+        ruleset = nft_ruleset(num, count, dns_num, mode, generation),
+    );
+    nft(&script)
+}
+
+fn nft_remove_nfqueue() -> Result<(), io::Error> {
+    nft(&format!("delete table inet {}\n", NFT_TABLE))
+}
+
+struct IptablesRule {
+    table: &'static str,
+    chain: &'static str,
+    rule: Vec<String>,
+}
+
+impl IptablesRule {
+    fn new(table: &'static str, chain: &'static str, rule: &[&[&str]]) -> Self {
+        IptablesRule {
+            table,
+            chain,
+            rule: rule.concat().into_iter().map(String::from).collect(),
+        }
+    }
+}
+
+const CHAINS: [(&str, &str); 2] = [("INPUT", "-i"), ("OUTPUT", "-o")];
+
+/// Rules in the order they should appear
+fn iptables_rules(num: u16, count: u16, dns_num: u16, mode: QueueMode) -> Vec<IptablesRule> {
+    let (queue_arg, num) = if count == 1 {
+        ("--queue-num", num.to_string())
+    } else {
+        ("--queue-balance", format!("{}:{}", num, num + count - 1))
+    };
+    let any_mark = format!("0/{:#x}", MARK_MASK);
+    let mask = format!("{:#x}", MARK_MASK);
+    let drop = format!("{:#x}/{:#x}", MARK_DROP, MARK_DROP);
+    let reject = format!("{:#x}/{:#x}", MARK_REJECT, MARK_REJECT);
+    let queue: &[&str] = &["-j", "NFQUEUE", queue_arg, &num, "--queue-bypass"];
+    let dns_port = dns::PORT.to_string();
+    let dns_num = dns_num.to_string();
+    let dns_queue: &[&str] = &["-j", "NFQUEUE", "--queue-num", &dns_num, "--queue-bypass"];
+    let mut rules = Vec::new();
+    for &(chain, iface) in &CHAINS {
+        let not_lo: &[&str] = &["!", iface, "lo"];
+        // verdicts of DNS flows are never cached, the cached rules above don't match them
+        if chain == "INPUT" {
+            for &proto in &["udp", "tcp"] {
+                let response = &["-p", proto, "--sport", &*dns_port];
+                rules.push(IptablesRule::new(
+                    "mangle",
+                    chain,
+                    &[not_lo, response, dns_queue],
+                ));
+            }
+        }
+        match mode {
+            QueueMode::All => {
+                rules.push(IptablesRule::new("mangle", chain, &[not_lo, queue]));
+            }
+            QueueMode::Uncached(mark) => {
+                let mark = format!("{:#x}/{:#x}", mark, MARK_MASK);
+                let uncached = &["-m", "connmark", "!", "--mark", &*mark];
+                rules.push(IptablesRule::new(
+                    "mangle",
+                    chain,
+                    &[not_lo, uncached, queue],
+                ));
+            }
+        }
+        let save_mark = &[
+            "-m",
+            "mark",
+            "!",
+            "--mark",
+            &*any_mark,
+            "-j",
+            "CONNMARK",
+            "--save-mark",
+            "--mask",
+            &*mask,
+        ];
+        rules.push(IptablesRule::new("filter", chain, &[save_mark]));
+        let drop_marked = &["-m", "mark", "--mark", &*drop, "-j", "DROP"];
+        rules.push(IptablesRule::new("filter", chain, &[drop_marked]));
+        // REJECT only works in the filter table
+        let reject_marked = &["-m", "mark", "--mark", &*reject];
+        let tcp_reset = &["-p", "tcp", "-j", "REJECT", "--reject-with", "tcp-reset"];
+        rules.push(IptablesRule::new(
+            "filter",
+            chain,
+            &[reject_marked, tcp_reset],
+        ));
+        // the default is port-unreachable, for both ICMP and ICMPv6
+        let port_unreachable = &["-j", "REJECT"];
+        rules.push(IptablesRule::new(
+            "filter",
+            chain,
+            &[reject_marked, port_unreachable],
+        ));
+    }
+    rules
+}
+
+/// Must be above the rules from `iptables_rules`
+fn iptables_cached_rules(generation: u32) -> Vec<IptablesRule> {
+    let mut rules = Vec::new();
+    let mask = format!("{:#x}", MARK_MASK);
+    // rejected by the rules from `iptables_rules` in the filter table
+    let restore_mark: &[&str] = &["-j", "CONNMARK", "--restore-mark", "--mask", &mask];
+    for &(chain, iface) in &CHAINS {
+        for &verdict in &[MARK_ACCEPT, MARK_DROP, MARK_REJECT] {
+            let mark = format!("{:#x}/{:#x}", verdict_mark(verdict, generation), MARK_MASK);
+            let marked = &["!", iface, "lo", "-m", "connmark", "--mark", &*mark];
+            if verdict == MARK_REJECT {
+                rules.push(IptablesRule::new("mangle", chain, &[marked, restore_mark]));
+            }
+            let target = if verdict == MARK_DROP {
+                "DROP"
+            } else {
+                "ACCEPT"
+            };
+            rules.push(IptablesRule::new(
+                "mangle",
+                chain,
+                &[marked, &["-j", target]],
+            ));
+        }
+    }
+    rules
+}
+
+fn iptables(v4: bool, cmd: &str, rule: &IptablesRule) -> Command {
     let mut c = Command::new(if v4 { "iptables" } else { "ip6tables" });
     c.stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::null())
-        .arg(format!("-{}", cmd))
-        .arg(if output { "OUTPUT" } else { "INPUT" })
-        .arg("!")
-        .arg(if output { "-o" } else { "-i" })
-        .arg("lo")
         .arg("-t")
-        .arg("mangle")
-        .arg("-j")
-        .arg("NFQUEUE")
-        .arg("--queue-num")
-        .arg(queue_num.to_string())
-        .arg("--queue-bypass");
+        .arg(rule.table)
+        .arg(format!("-{}", cmd))
+        .arg(rule.chain)
+        .args(&rule.rule);
     c
 }
 
-fn iptables_insert_nfqueue(num: u16) {
-    fn insert_if_not_exists(v4: bool, output: bool, num: u16) {
-        let rule_existed = iptables(v4, output, "C", num).status().unwrap().success();
-        if !rule_existed {
-            iptables(v4, output, "I", num).status().unwrap().success();
+fn iptables_insert(rules: &[IptablesRule]) {
+    // -I inserts to the top of chain, so the last rule goes first
+    for rule in rules.iter().rev() {
+        for &v4 in &[false, true] {
+            let rule_existed = iptables(v4, "C", rule).status().unwrap().success();
+            if !rule_existed {
+                iptables(v4, "I", rule).status().unwrap().success();
+            }
         }
     }
-
-    insert_if_not_exists(false, false, num);
-    insert_if_not_exists(true, false, num);
-    insert_if_not_exists(false, true, num);
-    insert_if_not_exists(true, true, num);
 }
 
-fn iptables_remove_nfqueue(num: u16) {
-    iptables(false, false, "D", num).status().unwrap().success();
-    iptables(true, false, "D", num).status().unwrap().success();
-    iptables(false, true, "D", num).status().unwrap().success();
-    iptables(true, true, "D", num).status().unwrap().success();
+fn iptables_remove(rules: &[IptablesRule]) {
+    for rule in rules {
+        for &v4 in &[false, true] {
+            iptables(v4, "D", rule).status().unwrap().success();
+        }
+    }
 }
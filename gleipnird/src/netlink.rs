@@ -1,3 +1,4 @@
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::{cmp, fmt, io, mem, net};
 
 use gleipnir_interface::Proto;
@@ -7,6 +8,9 @@ use pnetlink::{
     socket::{NetlinkProtocol, NetlinkSocket},
 };
 
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const INET_DIAG_NOCOOKIE: u32 = !0;
+
 pub struct SockDiag {
     socket: NetlinkSocket,
 }
@@ -17,36 +21,16 @@ impl SockDiag {
         Ok(SockDiag { socket })
     }
 
+    /// Every socket bound to these addresses, there are more than one with `SO_REUSEPORT`
     pub fn query<'a>(
         &'a mut self,
         protocol: Proto,
         local_address: net::SocketAddr,
         remote_address: net::SocketAddr,
-    ) -> Result<InetDiagMsg, io::Error> {
-        const SOCK_DIAG_BY_FAMILY: u16 = 20;
-        const INET_DIAG_NOCOOKIE: u32 = !0;
-
+    ) -> Result<Vec<InetDiagMsg>, io::Error> {
         assert_eq!(local_address.is_ipv4(), remote_address.is_ipv4());
 
-        let req = InetDiagReqV2 {
-            sdiag_family: if local_address.is_ipv4() {
-                libc::AF_INET
-            } else {
-                libc::AF_INET6
-            } as u8,
-            sdiag_protocol: protocol as u8,
-            idiag_ext: 0,
-            pad: 0,
-            idiag_states: !0, // any state
-            id: InetDiagSockId {
-                idiag_sport: local_address.port().into(),
-                idiag_dport: remote_address.port().into(),
-                idiag_src: local_address.ip().into(),
-                idiag_dst: remote_address.ip().into(),
-                idiag_if: 0,
-                idiag_cookie: [INET_DIAG_NOCOOKIE; 2],
-            },
-        };
+        let req = InetDiagReqV2::new(protocol, local_address, remote_address);
 
         let mut flags = NetlinkMsgFlags::NLM_F_REQUEST;
         if protocol != Proto::Tcp {
@@ -59,7 +43,7 @@ impl SockDiag {
             .build();
         self.socket.send(req.packet())?;
 
-        let mut r = None;
+        let mut r = Vec::new();
         let responses = NetlinkReader::new(&mut self.socket);
         for msg in responses {
             let diag_msg = msg.payload() as *const _ as *const InetDiagMsg;
@@ -69,13 +53,15 @@ impl SockDiag {
                 && diag_msg.id.idiag_sport == local_address.port()
                 && diag_msg.id.idiag_dst == remote_address.ip()
                 && diag_msg.id.idiag_dport == remote_address.port()
-                && diag_msg.idiag_inode != 0
             {
-                r = Some(*diag_msg);
+                r.push(*diag_msg);
             }
         }
 
-        r.ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+        if r.is_empty() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(r)
 
         // let n = self.socket.recv(&mut self.buf)?;
         // if let Some(msg) = NetlinkIterable::new(&self.buf[..n]).next() {
@@ -100,6 +86,441 @@ impl SockDiag {
         //     Err(io::Error::from(io::ErrorKind::NotFound))
         // }
     }
+
+    /// Every socket of a protocol and address family, in one request
+    pub fn dump(&mut self, protocol: Proto, ipv4: bool) -> Result<Vec<InetDiagMsg>, io::Error> {
+        let unspecified: net::IpAddr = if ipv4 {
+            net::Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            net::Ipv6Addr::UNSPECIFIED.into()
+        };
+        let unspecified = net::SocketAddr::new(unspecified, 0);
+        let req = InetDiagReqV2::new(protocol, unspecified, unspecified);
+        let req = NetlinkRequestBuilder::new(SOCK_DIAG_BY_FAMILY, NetlinkMsgFlags::NLM_F_DUMP)
+            .append(req)
+            .build();
+        self.socket.send(req.packet())?;
+
+        let mut sockets = Vec::new();
+        for msg in NetlinkReader::new(&mut self.socket) {
+            if msg.get_kind() != SOCK_DIAG_BY_FAMILY
+                || msg.payload().len() < mem::size_of::<InetDiagMsg>()
+            {
+                continue;
+            }
+            let diag_msg =
+                unsafe { (msg.payload().as_ptr() as *const InetDiagMsg).read_unaligned() };
+            sockets.push(diag_msg);
+        }
+        Ok(sockets)
+    }
+}
+
+/// TCP and UDP sockets closed by their owners, needs `CAP_NET_ADMIN`
+pub struct SockDestroyed {
+    fd: RawFd,
+    buf: Vec<u8>,
+}
+
+const NETLINK_SOCK_DIAG: libc::c_int = 4;
+/// `SKNLGRP_INET_TCP_DESTROY`, `SKNLGRP_INET_UDP_DESTROY`, and the same for IPv6
+const SKNLGRP_DESTROY: u32 = 0b1111;
+
+impl SockDestroyed {
+    pub fn new() -> io::Result<SockDestroyed> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                NETLINK_SOCK_DIAG,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let destroyed = SockDestroyed {
+            fd,
+            buf: vec![0; 32 * 1024],
+        };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = SKNLGRP_DESTROY;
+        let r = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(destroyed)
+    }
+
+    /// Never blocks, fails with `ENOBUFS` if some of them were lost
+    pub fn recv(&mut self) -> io::Result<Vec<InetDiagMsg>> {
+        let mut sockets = Vec::new();
+        loop {
+            let n = unsafe {
+                libc::recv(
+                    self.fd,
+                    self.buf.as_mut_ptr() as *mut libc::c_void,
+                    self.buf.len(),
+                    0,
+                )
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(sockets);
+                }
+                return Err(e);
+            }
+            sockets.extend(parse_diag_msgs(&self.buf[..n as usize]));
+        }
+    }
+}
+
+impl AsRawFd for SockDestroyed {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for SockDestroyed {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn parse_diag_msgs(mut buf: &[u8]) -> Vec<InetDiagMsg> {
+    let mut sockets = Vec::new();
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+        let kind = u16::from_ne_bytes([buf[4], buf[5]]);
+        let payload = &buf[NLMSG_HDRLEN..len];
+        // aligned to 4 bytes
+        buf = &buf[cmp::min((len + 3) & !3, buf.len())..];
+        if kind == SOCK_DIAG_BY_FAMILY && payload.len() >= mem::size_of::<InetDiagMsg>() {
+            let diag_msg = unsafe { (payload.as_ptr() as *const InetDiagMsg).read_unaligned() };
+            sockets.push(diag_msg);
+        }
+    }
+    sockets
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ProcEvent {
+    Fork { parent: u32, child: u32 },
+    Exec { pid: u32 },
+    Exit { pid: u32 },
+}
+
+/// Process events from the kernel's proc connector, needs `CAP_NET_ADMIN`
+pub struct ProcConnector {
+    fd: RawFd,
+    buf: Vec<u8>,
+}
+
+const NETLINK_CONNECTOR: libc::c_int = 11;
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const NLMSG_HDRLEN: usize = 16;
+const CN_MSG_LEN: usize = 20;
+
+impl ProcConnector {
+    pub fn new() -> io::Result<ProcConnector> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                NETLINK_CONNECTOR,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let connector = ProcConnector {
+            fd,
+            buf: vec![0; 8192],
+        };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = CN_IDX_PROC;
+        let r = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // nlmsghdr, cn_msg, then the operation
+        let mut msg = Vec::with_capacity(NLMSG_HDRLEN + CN_MSG_LEN + 4);
+        let len = (NLMSG_HDRLEN + CN_MSG_LEN + 4) as u32;
+        msg.extend_from_slice(&len.to_ne_bytes());
+        msg.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+        msg.extend_from_slice(&0u16.to_ne_bytes()); // flags
+        msg.extend_from_slice(&0u32.to_ne_bytes()); // seq
+        msg.extend_from_slice(&(std::process::id()).to_ne_bytes());
+        msg.extend_from_slice(&CN_IDX_PROC.to_ne_bytes());
+        msg.extend_from_slice(&CN_VAL_PROC.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes()); // seq
+        msg.extend_from_slice(&0u32.to_ne_bytes()); // ack
+        msg.extend_from_slice(&4u16.to_ne_bytes()); // len
+        msg.extend_from_slice(&0u16.to_ne_bytes()); // flags
+        msg.extend_from_slice(&PROC_CN_MCAST_LISTEN.to_ne_bytes());
+        let r = unsafe { libc::send(fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(connector)
+    }
+
+    /// Blocks until there are events, fails with `ENOBUFS` if some of them were lost
+    pub fn recv(&mut self) -> io::Result<Vec<ProcEvent>> {
+        let n = unsafe {
+            libc::recv(
+                self.fd,
+                self.buf.as_mut_ptr() as *mut libc::c_void,
+                self.buf.len(),
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(parse_proc_events(&self.buf[..n as usize]))
+    }
+}
+
+impl Drop for ProcConnector {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Threads are ignored, every pid is a thread group id
+fn parse_proc_events(mut buf: &[u8]) -> Vec<ProcEvent> {
+    const PROC_EVENT_FORK: u32 = 0x0000_0001;
+    const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+    const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+    // what, cpu, timestamp
+    const EVENT_HDRLEN: usize = 16;
+
+    let u32_at = |data: &[u8], offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_ne_bytes(bytes)
+    };
+
+    let mut events = Vec::new();
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32_at(buf, 0) as usize;
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+        let event = &buf[NLMSG_HDRLEN..len];
+        // aligned to 4 bytes
+        buf = &buf[cmp::min((len + 3) & !3, buf.len())..];
+        if event.len() < CN_MSG_LEN + EVENT_HDRLEN {
+            continue;
+        }
+        let event = &event[CN_MSG_LEN..];
+        let data = &event[EVENT_HDRLEN..];
+        let r = match u32_at(event, 0) {
+            // parent_pid, parent_tgid, child_pid, child_tgid
+            PROC_EVENT_FORK if data.len() >= 16 => {
+                let (child_pid, child_tgid) = (u32_at(data, 8), u32_at(data, 12));
+                if child_pid != child_tgid {
+                    continue;
+                }
+                ProcEvent::Fork {
+                    parent: u32_at(data, 4),
+                    child: child_tgid,
+                }
+            }
+            // process_pid, process_tgid
+            PROC_EVENT_EXEC if data.len() >= 8 => ProcEvent::Exec {
+                pid: u32_at(data, 4),
+            },
+            PROC_EVENT_EXIT if data.len() >= 8 => {
+                let (pid, tgid) = (u32_at(data, 0), u32_at(data, 4));
+                if pid != tgid {
+                    continue;
+                }
+                ProcEvent::Exit { pid: tgid }
+            }
+            _ => continue,
+        };
+        events.push(r);
+    }
+    events
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LinkEvent {
+    /// Added, renamed or otherwise changed
+    New {
+        index: u32,
+        name: String,
+    },
+    Del {
+        index: u32,
+    },
+}
+
+/// Changes of network interfaces from rtnetlink
+pub struct LinkMonitor {
+    fd: RawFd,
+    buf: Vec<u8>,
+}
+
+const RTMGRP_LINK: u32 = 1;
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_GETLINK: u16 = 18;
+/// family, type, index, flags and change
+const IFINFOMSG_LEN: usize = 16;
+const IFLA_IFNAME: u16 = 3;
+
+impl LinkMonitor {
+    pub fn new() -> io::Result<LinkMonitor> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let monitor = LinkMonitor {
+            fd,
+            // a dump is split into messages of a page or so
+            buf: vec![0; 64 * 1024],
+        };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = RTMGRP_LINK;
+        let r = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as u32,
+            )
+        };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(monitor)
+    }
+
+    /// Every interface comes from `recv` as `LinkEvent::New`
+    pub fn dump(&mut self) -> io::Result<()> {
+        let flags = (NetlinkMsgFlags::NLM_F_REQUEST | NetlinkMsgFlags::NLM_F_DUMP).bits();
+        // nlmsghdr, then an empty ifinfomsg
+        let len = NLMSG_HDRLEN + IFINFOMSG_LEN;
+        let mut msg = Vec::with_capacity(len);
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&RTM_GETLINK.to_ne_bytes());
+        msg.extend_from_slice(&flags.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes()); // seq
+        msg.extend_from_slice(&0u32.to_ne_bytes()); // pid
+        msg.resize(len, 0); // AF_UNSPEC
+        let r = unsafe { libc::send(self.fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if r < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Blocks until there are events, fails with `ENOBUFS` if some of them were lost
+    pub fn recv(&mut self) -> io::Result<Vec<LinkEvent>> {
+        let n = unsafe {
+            libc::recv(
+                self.fd,
+                self.buf.as_mut_ptr() as *mut libc::c_void,
+                self.buf.len(),
+                0,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(parse_link_events(&self.buf[..n as usize]))
+    }
+}
+
+impl Drop for LinkMonitor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn parse_link_events(mut buf: &[u8]) -> Vec<LinkEvent> {
+    const RTA_HDRLEN: usize = 4;
+
+    let u16_at = |data: &[u8], offset: usize| u16::from_ne_bytes([data[offset], data[offset + 1]]);
+    let u32_at = |data: &[u8], offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_ne_bytes(bytes)
+    };
+
+    let mut events = Vec::new();
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32_at(buf, 0) as usize;
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            break;
+        }
+        let kind = u16_at(buf, 4);
+        let msg = &buf[NLMSG_HDRLEN..len];
+        // aligned to 4 bytes
+        buf = &buf[cmp::min((len + 3) & !3, buf.len())..];
+        if msg.len() < IFINFOMSG_LEN {
+            continue;
+        }
+        let index = u32_at(msg, 4);
+        let r = match kind {
+            RTM_NEWLINK => {
+                let mut attrs = &msg[IFINFOMSG_LEN..];
+                let mut name = None;
+                while attrs.len() >= RTA_HDRLEN {
+                    let len = u16_at(attrs, 0) as usize;
+                    if len < RTA_HDRLEN || len > attrs.len() {
+                        break;
+                    }
+                    if u16_at(attrs, 2) == IFLA_IFNAME {
+                        let value = &attrs[RTA_HDRLEN..len];
+                        let value = value.split(|&b| b == 0).next().unwrap_or_default();
+                        name = Some(String::from_utf8_lossy(value).into_owned());
+                    }
+                    attrs = &attrs[cmp::min((len + 3) & !3, attrs.len())..];
+                }
+                match name {
+                    Some(name) => LinkEvent::New { index, name },
+                    None => continue,
+                }
+            }
+            RTM_DELLINK => LinkEvent::Del { index },
+            _ => continue,
+        };
+        events.push(r);
+    }
+    events
 }
 
 #[repr(C)]
@@ -113,6 +534,34 @@ struct InetDiagReqV2 {
     id: InetDiagSockId,
 }
 
+impl InetDiagReqV2 {
+    fn new(
+        protocol: Proto,
+        local_address: net::SocketAddr,
+        remote_address: net::SocketAddr,
+    ) -> Self {
+        InetDiagReqV2 {
+            sdiag_family: if local_address.is_ipv4() {
+                libc::AF_INET
+            } else {
+                libc::AF_INET6
+            } as u8,
+            sdiag_protocol: protocol as u8,
+            idiag_ext: 0,
+            pad: 0,
+            idiag_states: !0, // any state
+            id: InetDiagSockId {
+                idiag_sport: local_address.port().into(),
+                idiag_dport: remote_address.port().into(),
+                idiag_src: local_address.ip().into(),
+                idiag_dst: remote_address.ip().into(),
+                idiag_if: 0,
+                idiag_cookie: [INET_DIAG_NOCOOKIE; 2],
+            },
+        }
+    }
+}
+
 impl Packet for InetDiagReqV2 {
     fn packet(&self) -> &[u8] {
         let p: &[u8; mem::size_of::<Self>()] = unsafe { mem::transmute(self) };
@@ -144,6 +593,31 @@ pub struct InetDiagMsg {
     pub idiag_inode: u32,
 }
 
+impl InetDiagMsg {
+    /// Same as `bpf_get_socket_cookie`
+    pub fn cookie(&self) -> u64 {
+        (self.id.idiag_cookie[1] as u64) << 32 | self.id.idiag_cookie[0] as u64
+    }
+
+    pub fn local_address(&self) -> net::SocketAddr {
+        self.address(self.id.idiag_src, self.id.idiag_sport)
+    }
+
+    /// Unspecified for listeners and unconnected UDP sockets
+    pub fn remote_address(&self) -> net::SocketAddr {
+        self.address(self.id.idiag_dst, self.id.idiag_dport)
+    }
+
+    fn address(&self, ip: Ipv4or6, port: Port) -> net::SocketAddr {
+        let ip: net::IpAddr = if self.idiag_family == libc::AF_INET as u8 {
+            net::Ipv4Addr::from(ip).into()
+        } else {
+            net::Ipv6Addr::from(ip).into()
+        };
+        net::SocketAddr::new(ip, port.into())
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct InetDiagSockId {
@@ -300,3 +774,126 @@ fn port_convert_u16() {
     let port = Port::from(1234);
     assert_eq!(u16::from(port), 1234);
 }
+
+#[test]
+fn destroyed_sockets() {
+    let local: net::SocketAddr = "[2001:db8::1]:1234".parse().unwrap();
+    let remote: net::SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+    let diag_msg = InetDiagMsg {
+        idiag_family: libc::AF_INET6 as u8,
+        idiag_state: 0,
+        idiag_timer: 0,
+        idiag_retrans: 0,
+        id: InetDiagSockId {
+            idiag_sport: local.port().into(),
+            idiag_dport: remote.port().into(),
+            idiag_src: local.ip().into(),
+            idiag_dst: remote.ip().into(),
+            idiag_if: 0,
+            idiag_cookie: [1, 2],
+        },
+        idiag_expires: 0,
+        idiag_rqueue: 0,
+        idiag_wqueue: 0,
+        idiag_uid: 1000,
+        idiag_inode: 42,
+    };
+    let payload: [u8; mem::size_of::<InetDiagMsg>()] = unsafe { mem::transmute(diag_msg) };
+    // with attributes after it
+    let len = NLMSG_HDRLEN + payload.len() + 6;
+    let mut msg = (len as u32).to_ne_bytes().to_vec();
+    msg.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    msg.resize(NLMSG_HDRLEN, 0);
+    msg.extend_from_slice(&payload);
+    msg.resize((len + 3) & !3, 0);
+    let mut buf = msg.clone();
+    // NLMSG_DONE
+    buf.extend_from_slice(&(NLMSG_HDRLEN as u32).to_ne_bytes());
+    buf.extend_from_slice(&(libc::NLMSG_DONE as u16).to_ne_bytes());
+    buf.resize(buf.len() + NLMSG_HDRLEN - 6, 0);
+    buf.extend_from_slice(&msg);
+
+    let sockets = parse_diag_msgs(&buf);
+    assert_eq!(sockets.len(), 2);
+    for socket in sockets {
+        assert_eq!(socket.local_address(), local);
+        assert_eq!(socket.remote_address(), remote);
+        assert_eq!(socket.cookie(), 2 << 32 | 1);
+        assert_eq!(socket.idiag_inode, 42);
+    }
+}
+
+#[test]
+fn link_events() {
+    fn link(kind: u16, index: u32, attrs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut msg = vec![0; NLMSG_HDRLEN];
+        msg[4..6].copy_from_slice(&kind.to_ne_bytes());
+        msg.extend_from_slice(&[0; 4]);
+        msg.extend_from_slice(&index.to_ne_bytes());
+        msg.extend_from_slice(&[0; 8]);
+        for (kind, value) in attrs {
+            let len = 4 + value.len();
+            msg.extend_from_slice(&(len as u16).to_ne_bytes());
+            msg.extend_from_slice(&kind.to_ne_bytes());
+            msg.extend_from_slice(value);
+            msg.resize((msg.len() + 3) & !3, 0);
+        }
+        let len = msg.len() as u32;
+        msg[..4].copy_from_slice(&len.to_ne_bytes());
+        msg
+    }
+    // IFLA_MTU before the name
+    let mut buf = link(RTM_NEWLINK, 1, &[(4, &1500u32.to_ne_bytes()), (3, b"lo\0")]);
+    buf.extend(link(RTM_NEWLINK, 3, &[(3, b"wg0\0")]));
+    // no name, ignored
+    buf.extend(link(RTM_NEWLINK, 4, &[(4, &1420u32.to_ne_bytes())]));
+    buf.extend(link(RTM_DELLINK, 3, &[(3, b"wg0\0")]));
+    assert_eq!(
+        parse_link_events(&buf),
+        vec![
+            LinkEvent::New {
+                index: 1,
+                name: "lo".to_owned()
+            },
+            LinkEvent::New {
+                index: 3,
+                name: "wg0".to_owned()
+            },
+            LinkEvent::Del { index: 3 },
+        ]
+    );
+}
+
+#[test]
+fn proc_events() {
+    fn event(what: u32, data: &[u32]) -> Vec<u8> {
+        let len = NLMSG_HDRLEN + CN_MSG_LEN + 16 + data.len() * 4;
+        let mut msg = (len as u32).to_ne_bytes().to_vec();
+        msg.resize(NLMSG_HDRLEN + CN_MSG_LEN, 0);
+        msg.extend_from_slice(&what.to_ne_bytes());
+        msg.resize(len - data.len() * 4, 0);
+        for v in data {
+            msg.extend_from_slice(&v.to_ne_bytes());
+        }
+        msg
+    }
+    let mut buf = event(1, &[10, 10, 20, 20]);
+    // a new thread
+    buf.extend(event(1, &[20, 20, 21, 20]));
+    buf.extend(event(2, &[20, 20]));
+    buf.extend(event(0x8000_0000, &[21, 20, 0, 0]));
+    buf.extend(event(0x8000_0000, &[20, 20, 0, 0]));
+    // uid change, ignored
+    buf.extend(event(4, &[20, 20, 0, 0]));
+    assert_eq!(
+        parse_proc_events(&buf),
+        vec![
+            ProcEvent::Fork {
+                parent: 10,
+                child: 20
+            },
+            ProcEvent::Exec { pid: 20 },
+            ProcEvent::Exit { pid: 20 },
+        ]
+    );
+}
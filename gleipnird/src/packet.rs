@@ -0,0 +1,243 @@
+//! Locate the transport header of IPv4 and IPv6 packets
+//!
+//! IPv4 options and IPv6 extension headers are skipped. A fragment other than the first one
+//! has no transport header, so there is nothing to match it against; the first fragment
+//! carries the verdict, and a dropped first fragment makes the whole packet unusable anyway.
+//! With conntrack loaded the kernel reassembles packets before NFQUEUE, fragments are rare.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    /// The packet ends in the middle of a header
+    Truncated,
+    /// Not IPv4 or IPv6, or an invalid header length
+    Malformed,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct IpPacket<'a> {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    /// The protocol of the transport header, `Ipv6NoNxt` and `Esp` can't be looked into
+    pub protocol: IpNextHeaderProtocol,
+    /// The transport header and the rest, `None` for non-first fragments
+    pub transport: Option<&'a [u8]>,
+}
+
+pub fn parse(payload: &[u8]) -> Result<IpPacket, ParseError> {
+    match payload.first().map(|b| b >> 4) {
+        Some(4) => parse_v4(payload),
+        Some(6) => parse_v6(payload),
+        Some(_) => Err(ParseError::Malformed),
+        None => Err(ParseError::Truncated),
+    }
+}
+
+fn parse_v4(payload: &[u8]) -> Result<IpPacket, ParseError> {
+    const MIN_LEN: usize = 20;
+    if payload.len() < MIN_LEN {
+        return Err(ParseError::Truncated);
+    }
+    let header_len = (payload[0] & 0x0f) as usize * 4;
+    if header_len < MIN_LEN {
+        return Err(ParseError::Malformed);
+    }
+    if payload.len() < header_len {
+        return Err(ParseError::Truncated);
+    }
+    let fragment_offset = u16::from_be_bytes([payload[6], payload[7]]) & 0x1fff;
+    let src = Ipv4Addr::new(payload[12], payload[13], payload[14], payload[15]);
+    let dst = Ipv4Addr::new(payload[16], payload[17], payload[18], payload[19]);
+    Ok(IpPacket {
+        src: src.into(),
+        dst: dst.into(),
+        protocol: IpNextHeaderProtocol(payload[9]),
+        transport: if fragment_offset == 0 {
+            Some(&payload[header_len..])
+        } else {
+            None
+        },
+    })
+}
+
+fn parse_v6(payload: &[u8]) -> Result<IpPacket, ParseError> {
+    const HEADER_LEN: usize = 40;
+    if payload.len() < HEADER_LEN {
+        return Err(ParseError::Truncated);
+    }
+    let mut addrs = [[0u8; 16]; 2];
+    addrs[0].copy_from_slice(&payload[8..24]);
+    addrs[1].copy_from_slice(&payload[24..40]);
+
+    let mut protocol = IpNextHeaderProtocol(payload[6]);
+    let mut offset = HEADER_LEN;
+    let mut first_fragment = true;
+    loop {
+        let ext_len = match protocol {
+            IpNextHeaderProtocols::Hopopt
+            | IpNextHeaderProtocols::Ipv6Route
+            | IpNextHeaderProtocols::Ipv6Opts
+            | IpNextHeaderProtocols::MobilityHeader
+            | IpNextHeaderProtocols::Hip
+            | IpNextHeaderProtocols::Shim6 => {
+                let len = payload.get(offset + 1).ok_or(ParseError::Truncated)?;
+                (*len as usize + 1) * 8
+            }
+            // the length is in 4-octet units, not counting the first 2
+            IpNextHeaderProtocols::Ah => {
+                let len = payload.get(offset + 1).ok_or(ParseError::Truncated)?;
+                (*len as usize + 2) * 4
+            }
+            IpNextHeaderProtocols::Ipv6Frag => {
+                let header = payload
+                    .get(offset..offset + 4)
+                    .ok_or(ParseError::Truncated)?;
+                let fragment_offset = u16::from_be_bytes([header[2], header[3]]) >> 3;
+                first_fragment = fragment_offset == 0;
+                8
+            }
+            _ => break,
+        };
+        if payload.len() < offset + ext_len {
+            return Err(ParseError::Truncated);
+        }
+        protocol = IpNextHeaderProtocol(payload[offset]);
+        offset += ext_len;
+        if !first_fragment {
+            // the following headers are in the first fragment
+            break;
+        }
+    }
+
+    Ok(IpPacket {
+        src: Ipv6Addr::from(addrs[0]).into(),
+        dst: Ipv6Addr::from(addrs[1]).into(),
+        protocol,
+        transport: if first_fragment {
+            Some(&payload[offset..])
+        } else {
+            None
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TCP_HEADER: [u8; 20] = [
+        0x01, 0xbb, 0x00, 0x50, 0, 0, 0, 0, 0, 0, 0, 0, 0x50, 0x02, 0xff, 0xff, 0, 0, 0, 0,
+    ];
+
+    fn ipv4(ihl: u8, fragment_offset: u16) -> Vec<u8> {
+        let mut pkt = vec![0x40 | ihl, 0, 0, 0, 0, 0];
+        pkt.extend_from_slice(&fragment_offset.to_be_bytes());
+        pkt.extend_from_slice(&[64, 6, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2]);
+        // options
+        pkt.resize(ihl as usize * 4, 1);
+        pkt.extend_from_slice(&TCP_HEADER);
+        pkt
+    }
+
+    fn ipv6(next_header: u8, ext_headers: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![0x60, 0, 0, 0, 0, 0, next_header, 64];
+        pkt.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        pkt.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        pkt.extend_from_slice(ext_headers);
+        pkt.extend_from_slice(&TCP_HEADER);
+        pkt
+    }
+
+    #[test]
+    fn ipv4_options() {
+        for &ihl in &[5, 6, 15] {
+            let pkt = ipv4(ihl, 0);
+            let r = parse(&pkt).unwrap();
+            assert_eq!(r.src, IpAddr::from([1, 1, 1, 1]));
+            assert_eq!(r.dst, IpAddr::from([2, 2, 2, 2]));
+            assert_eq!(r.protocol, IpNextHeaderProtocols::Tcp);
+            assert_eq!(r.transport, Some(&TCP_HEADER[..]));
+        }
+    }
+
+    #[test]
+    fn ipv4_fragments() {
+        // more fragments flag, offset 0
+        let pkt = ipv4(5, 0x2000);
+        let r = parse(&pkt).unwrap();
+        assert_eq!(r.transport, Some(&TCP_HEADER[..]));
+        let pkt = ipv4(5, 185);
+        let r = parse(&pkt).unwrap();
+        assert_eq!(r.protocol, IpNextHeaderProtocols::Tcp);
+        assert_eq!(r.transport, None);
+    }
+
+    #[test]
+    fn ipv4_invalid() {
+        assert_eq!(parse(&ipv4(4, 0)), Err(ParseError::Malformed));
+        assert_eq!(parse(&ipv4(15, 0)[..40]), Err(ParseError::Truncated));
+        assert_eq!(parse(&[]), Err(ParseError::Truncated));
+        assert_eq!(parse(&[0x50; 40]), Err(ParseError::Malformed));
+    }
+
+    #[test]
+    fn ipv6_extension_headers() {
+        let pkt = ipv6(6, &[]);
+        let r = parse(&pkt).unwrap();
+        assert_eq!(r.protocol, IpNextHeaderProtocols::Tcp);
+        assert_eq!(r.transport, Some(&TCP_HEADER[..]));
+
+        // hop-by-hop (8 bytes) -> routing (16 bytes) -> destination options (8 bytes) -> TCP
+        let mut ext = vec![43, 0, 1, 4, 0, 0, 0, 0];
+        ext.extend_from_slice(&[60, 1, 0, 0, 0, 0, 0, 0]);
+        ext.extend_from_slice(&[0; 8]);
+        ext.extend_from_slice(&[6, 0, 1, 4, 0, 0, 0, 0]);
+        let pkt = ipv6(0, &ext);
+        let r = parse(&pkt).unwrap();
+        assert_eq!(r.protocol, IpNextHeaderProtocols::Tcp);
+        assert_eq!(r.transport, Some(&TCP_HEADER[..]));
+
+        // authentication header (16 bytes) -> TCP
+        let mut ext = vec![6, 2, 0, 0];
+        ext.extend_from_slice(&[0; 12]);
+        let pkt = ipv6(51, &ext);
+        let r = parse(&pkt).unwrap();
+        assert_eq!(r.protocol, IpNextHeaderProtocols::Tcp);
+        assert_eq!(r.transport, Some(&TCP_HEADER[..]));
+    }
+
+    #[test]
+    fn ipv6_fragments() {
+        // first fragment, more fragments flag
+        let pkt = ipv6(44, &[6, 0, 0, 1, 0, 0, 0, 1]);
+        let r = parse(&pkt).unwrap();
+        assert_eq!(r.protocol, IpNextHeaderProtocols::Tcp);
+        assert_eq!(r.transport, Some(&TCP_HEADER[..]));
+
+        let pkt = ipv6(44, &[6, 0, 0x05, 0xc8, 0, 0, 0, 1]);
+        let r = parse(&pkt).unwrap();
+        assert_eq!(r.protocol, IpNextHeaderProtocols::Tcp);
+        assert_eq!(r.transport, None);
+    }
+
+    #[test]
+    fn ipv6_unsupported() {
+        let pkt = ipv6(50, &[]);
+        let r = parse(&pkt).unwrap();
+        assert_eq!(r.protocol, IpNextHeaderProtocols::Esp);
+        let pkt = ipv6(59, &[]);
+        let r = parse(&pkt).unwrap();
+        assert_eq!(r.protocol, IpNextHeaderProtocols::Ipv6NoNxt);
+    }
+
+    #[test]
+    fn ipv6_truncated() {
+        let pkt = ipv6(0, &[6, 1, 0, 0, 0, 0, 0, 0]);
+        // the hop-by-hop header claims 16 bytes, but there are only 8 + TCP_HEADER
+        assert_eq!(parse(&pkt[..40 + 12]), Err(ParseError::Truncated));
+        assert_eq!(parse(&pkt[..39]), Err(ParseError::Truncated));
+    }
+}
@@ -1,20 +1,40 @@
-use std::cell::RefCell;
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fs::{self, File};
 use std::io::{self, prelude::*};
-use std::path::PathBuf;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::thread;
+
+use gleipnir_interface::{Ancestor, OwnerPolicy};
+use lazy_static::lazy_static;
+
+use crate::netlink::{ProcConnector, ProcEvent};
 
 type Pid = usize;
 type Inode = u32;
 
 const PROC: &str = "/proc/";
+pub const KERNEL_EXE: &str = "[kernel]";
+/// Added to the `exe` link when the file is removed or replaced
+const DELETED_SUFFIX: &[u8] = b" (deleted)";
+/// Ancestors of a process in an AppImage looked at for its runtime, see `appimage`
+const MAX_APPIMAGE_DEPTH: usize = 16;
 
-thread_local! {
-    static PROC_CACHE: RefCell<ProcCache> = Default::default();
-    static INODE_INDEX: RefCell<HashMap<Inode, Pid>> = Default::default();
-    static PROC_INDEX: RefCell<HashMap<Pid, Process>> = Default::default();
+lazy_static! {
+    static ref TABLE: RwLock<ProcTable> = RwLock::new(ProcTable {
+        stale: true,
+        ..Default::default()
+    });
 }
 
+/// The proc connector keeps `TABLE` current, see `watch`
+static WATCHING: AtomicBool = AtomicBool::new(false);
+
 #[derive(Debug, Clone)]
 pub struct Process {
     pub pid: usize,
@@ -22,54 +42,289 @@ pub struct Process {
     pub ppid: usize,
     /// The process group ID of the process.
     pub pgrp: usize,
+    /// Clock ticks after boot, tells processes with a reused PID apart
+    pub starttime: u64,
+    /// The effective user ID of the process.
+    pub uid: u32,
+    /// The effective group ID of the process.
+    pub gid: u32,
+    /// The cgroup path, like `/system.slice/cron.service`, as it was when the process was
+    /// scanned, empty if unknown
+    pub cgroup: String,
+    /// Identifies sandboxed applications and containers, see `read_app_id`
+    pub app_id: Option<String>,
+    /// The path in the mount namespace of the process, the file of an AppImage, lossily
+    /// converted to UTF-8
     pub exe: String,
+    /// The executable has been replaced or removed since the process started
+    pub exe_deleted: bool,
+    /// Arguments, including `argv[0]`, lossily converted to UTF-8
+    pub cmdline: Vec<String>,
     pub inodes: Vec<Inode>,
 }
 
+/// A process, even after its PID is reused
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ProcId {
+    pub pid: Pid,
+    pub starttime: u64,
+}
+
+impl Process {
+    /// Owner of packets that don't belong to any process, like ICMP errors
+    pub fn kernel() -> Self {
+        Self::kernel_subsystem(None)
+    }
+
+    /// `[kernel:<subsystem>]`, for sockets created by the kernel or left behind by processes
+    pub fn kernel_subsystem(subsystem: Option<&str>) -> Self {
+        let exe = match subsystem {
+            Some(subsystem) => format!("[kernel:{}]", subsystem),
+            None => KERNEL_EXE.to_owned(),
+        };
+        Process {
+            pid: 0,
+            ppid: 0,
+            pgrp: 0,
+            starttime: 0,
+            uid: 0,
+            gid: 0,
+            cgroup: String::new(),
+            app_id: None,
+            exe,
+            exe_deleted: false,
+            cmdline: Vec::new(),
+            inodes: Vec::new(),
+        }
+    }
+
+    pub fn id(&self) -> ProcId {
+        ProcId {
+            pid: self.pid,
+            starttime: self.starttime,
+        }
+    }
+}
+
+/// Shared by all workers
 #[derive(Default)]
-struct ProcCache {
-    new: HashSet<Pid>,
-    old: HashSet<Pid>,
-    garbage: HashSet<Pid>,
-}
-
-pub fn get_proc_by_inode(inode: Inode) -> Option<Process> {
-    fn get(inode: Inode) -> Option<Process> {
-        let_tls!(inode_index, INODE_INDEX);
-        let inodes = inode_index.borrow();
-        inodes.get(&inode).map(|pid| {
-            let_tls!(proc_index, PROC_INDEX);
-            let procs = proc_index.borrow();
-            let proc = procs.get(pid).expect("broken cache");
-            proc.clone()
-        })
+struct ProcTable {
+    procs: HashMap<Pid, Process>,
+    /// Every process with the socket open, it's inherited by forks and may be passed around.
+    /// A socket of a process that has exited is not given to a new one with the same PID
+    inodes: HashMap<Inode, Vec<ProcId>>,
+    /// Forked or exec'd since they were scanned
+    dirty: HashSet<Pid>,
+    /// Parents of the forked processes in `dirty`, their sockets may have been inherited
+    forked: HashSet<Pid>,
+    /// Forked, exec'd or exited during a scan of `/proc`, which is older than the events,
+    /// see `start_scan`
+    changed: HashSet<Pid>,
+    /// Scans of `/proc` that are running outside the lock
+    scanning: usize,
+    /// All of `/proc` has to be scanned, at startup or after lost events
+    stale: bool,
+}
+
+impl ProcTable {
+    /// Empty if some of them have to be scanned again
+    fn get(&self, inode: Inode) -> Vec<Process> {
+        let ids = match self.inodes.get(&inode) {
+            Some(ids) => ids,
+            None => return Vec::new(),
+        };
+        if ids
+            .iter()
+            .any(|id| self.dirty.contains(&id.pid) || self.forked.contains(&id.pid))
+        {
+            return Vec::new();
+        }
+        ids.iter()
+            .filter_map(|id| {
+                self.procs
+                    .get(&id.pid)
+                    .filter(|proc| proc.starttime == id.starttime)
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn is_alive(&self, id: ProcId) -> bool {
+        self.procs
+            .get(&id.pid)
+            .map_or(false, |proc| proc.starttime == id.starttime)
+    }
+
+    fn insert(&mut self, proc: Process) {
+        self.remove(proc.pid);
+        for &inode in &proc.inodes {
+            self.inodes.entry(inode).or_default().push(proc.id());
+        }
+        self.procs.insert(proc.pid, proc);
+    }
+
+    fn remove(&mut self, pid: Pid) {
+        if let Some(proc) = self.procs.remove(&pid) {
+            for inode in &proc.inodes {
+                if let Some(ids) = self.inodes.get_mut(inode) {
+                    ids.retain(|id| *id != proc.id());
+                    if ids.is_empty() {
+                        self.inodes.remove(inode);
+                    }
+                }
+            }
+        }
+    }
+
+    fn scan(&mut self, pid: Pid) {
+        let path: PathBuf = format!("{}{}", PROC, pid).into();
+        match parse_proc_pid(path, pid) {
+            Ok(proc) => self.insert(proc),
+            Err(_) => self.remove(pid),
+        }
+    }
+
+    fn scan_dirty(&mut self) {
+        let dirty: Vec<_> = self.dirty.drain().collect();
+        self.forked.clear();
+        for pid in dirty {
+            self.scan(pid);
+        }
+    }
+
+    /// Processes that may have created the socket since they were scanned, the ones of its
+    /// user and the others
+    fn candidates(&self, uid: u32) -> (Vec<ProcId>, Vec<ProcId>) {
+        let (of_user, others): (Vec<_>, Vec<_>) =
+            self.procs.values().partition(|proc| proc.uid == uid);
+        (
+            of_user.into_iter().map(Process::id).collect(),
+            others.into_iter().map(Process::id).collect(),
+        )
     }
+
+    /// The socket has been found in the `fd` directory of these processes
+    fn add_owners(&mut self, inode: Inode, owners: Vec<ProcId>) {
+        for id in owners {
+            let proc = match self.procs.get_mut(&id.pid) {
+                Some(proc) if proc.starttime == id.starttime => proc,
+                // exited or rescanned in the meantime
+                _ => continue,
+            };
+            if !proc.inodes.contains(&inode) {
+                proc.inodes.push(inode);
+                self.inodes.entry(inode).or_default().push(id);
+            }
+        }
+    }
+
+    /// Before `/proc` is scanned outside the lock
+    fn start_scan(&mut self) {
+        self.stale = false;
+        self.scanning += 1;
+    }
+
+    /// Processes that changed during the scan are left to the events
+    fn finish_scan(&mut self, procs: Vec<Process>) {
+        let found: HashSet<Pid> = procs.iter().map(|proc| proc.pid).collect();
+        let gone: Vec<_> = self
+            .procs
+            .keys()
+            .filter(|pid| !found.contains(pid) && !self.changed.contains(pid))
+            .cloned()
+            .collect();
+        for pid in gone {
+            self.remove(pid);
+        }
+        for proc in procs {
+            if !self.changed.contains(&proc.pid) {
+                self.insert(proc);
+            }
+        }
+        self.scanning -= 1;
+        if self.scanning == 0 {
+            self.changed.clear();
+        }
+    }
+
+    fn changed(&mut self, pid: Pid) {
+        if self.scanning > 0 {
+            self.changed.insert(pid);
+        }
+    }
+
+    fn apply(&mut self, events: Vec<ProcEvent>) {
+        for event in events {
+            match event {
+                // scanned when they are looked up
+                ProcEvent::Fork { parent, child } => {
+                    self.dirty.insert(child as Pid);
+                    self.forked.insert(parent as Pid);
+                    self.changed(child as Pid);
+                }
+                ProcEvent::Exec { pid } => {
+                    self.dirty.insert(pid as Pid);
+                    self.changed(pid as Pid);
+                }
+                ProcEvent::Exit { pid } => {
+                    self.dirty.remove(&(pid as Pid));
+                    self.remove(pid as Pid);
+                    self.changed(pid as Pid);
+                }
+            }
+        }
+    }
+}
+
+/// Every process with the socket open, empty if there are none
+///
+/// New processes are scanned when they are looked up. A socket created later by a known
+/// process is searched for in the `fd` directories of the processes of its user, then of
+/// every other process, all of `/proc` is only scanned if events were lost. Neither is done
+/// while holding the lock.
+pub fn get_procs_by_inode(inode: Inode, uid: u32) -> Vec<Process> {
     if inode == 0 {
-        return None;
+        return Vec::new();
     }
-    get(inode)
-        .or_else(|| {
-            add_new_proc_to_cache();
-            get(inode)
-        })
-        .or_else(|| {
-            refresh_old_proc_in_cache();
-            get(inode)
-        })
+    // unless new processes may have inherited it
+    let procs = TABLE.read().unwrap().get(inode);
+    if !procs.is_empty() {
+        return procs;
+    }
+    let mut table = TABLE.write().unwrap();
+    // new processes are the most likely owners
+    table.scan_dirty();
+    let procs = table.get(inode);
+    if !procs.is_empty() {
+        return procs;
+    }
+    if table.stale || !WATCHING.load(Ordering::Acquire) {
+        table.start_scan();
+        drop(table);
+        let procs = scan_all();
+        let mut table = TABLE.write().unwrap();
+        table.finish_scan(procs);
+        return table.get(inode);
+    }
+    let (of_user, others) = table.candidates(uid);
+    drop(table);
+    let has_socket = |id: &ProcId| {
+        let path: PathBuf = format!("{}{}/fd", PROC, id.pid).into();
+        read_inodes(&path).map_or(false, |inodes| inodes.contains(&inode))
+    };
+    let mut owners: Vec<_> = of_user.into_iter().filter(has_socket).collect();
+    // created before a setuid, like a daemon that dropped its privileges
+    if owners.is_empty() {
+        owners = others.into_iter().filter(has_socket).collect();
+    }
+    let mut table = TABLE.write().unwrap();
+    table.add_owners(inode, owners);
+    table.get(inode)
 }
 
-fn add_new_proc_to_cache() {
-    let_tls!(proc_cache, PROC_CACHE);
-    let_tls!(inode_index, INODE_INDEX);
-    let_tls!(proc_index, PROC_INDEX);
-    let mut cache = proc_cache.borrow_mut();
-    let mut inodes = inode_index.borrow_mut();
-    let mut procs = proc_index.borrow_mut();
-    let ProcCache { new, old, garbage } = &mut *cache;
-    garbage.clear();
-    garbage.extend(new.drain());
-    garbage.extend(old.drain());
-    for (entry, pid) in fs::read_dir(PROC)
+/// Every process in `/proc`, see `ProcTable::start_scan`
+fn scan_all() -> Vec<Process> {
+    fs::read_dir(PROC)
         .expect("open /proc")
         .map(|e| e.expect("visit /proc"))
         .filter_map(|e| {
@@ -79,98 +334,533 @@ fn add_new_proc_to_cache() {
                 .expect("no file_name")
                 .to_str()
                 .expect("file_name not a vaild UTF-8");
-            match file_name.parse::<Pid>() {
-                Ok(pid) => Some((e, pid)),
-                _ => None,
-            }
+            let pid = file_name.parse::<Pid>().ok()?;
+            // exited in the meantime
+            parse_proc_pid(path, pid).ok()
         })
-    {
-        if garbage.remove(&pid) {
-            old.insert(pid);
-        } else {
-            let proc = match parse_proc_pid(entry.path(), pid) {
-                Ok(r) => r,
-                Err(_) => continue,
-            };
-            for &inode in &proc.inodes {
-                inodes.insert(inode, pid);
-            }
-            procs.insert(pid, proc);
-            new.insert(pid);
-        }
-    }
-    for pid in &*garbage {
-        let proc = procs.remove(pid).expect("");
-        for inode in &proc.inodes {
-            inodes.remove(inode);
-        }
-    }
-}
-
-fn refresh_old_proc_in_cache() {
-    PROC_CACHE.with(|proc_cache| {
-        INODE_INDEX.with(|inode_index| {
-            PROC_INDEX.with(|proc_index| {
-                let mut cache = proc_cache.borrow_mut();
-                let mut inodes = inode_index.borrow_mut();
-                let mut procs = proc_index.borrow_mut();
-                let ProcCache { new, old, .. } = &mut *cache;
-                for pid in old.drain() {
-                    let path: PathBuf = format!("{}{}", PROC, pid).into();
-                    if let Ok(proc) = parse_proc_pid(path, pid) {
-                        for &inode in &proc.inodes {
-                            inodes.insert(inode, pid);
-                        }
-                        procs.insert(pid, proc);
-                        new.insert(pid);
-                    } else {
-                        let proc = procs.remove(&pid).expect("");
-                        for inode in &proc.inodes {
-                            inodes.remove(inode);
-                        }
-                    }
+        .collect()
+}
+
+/// Puts the preferred owner of a socket first, `OwnerPolicy::All` checks them in this order
+pub fn sort_owners(procs: &mut [Process], policy: OwnerPolicy) {
+    if procs.len() < 2 {
+        return;
+    }
+    match policy {
+        OwnerPolicy::LatestExec | OwnerPolicy::All => {
+            procs.sort_by_key(|proc| Reverse(proc.starttime))
+        }
+        // an unrelated process the socket was passed to descends from nobody
+        OwnerPolicy::Parent => {
+            let mut descendants: HashMap<Pid, usize> = HashMap::new();
+            for proc in procs.iter() {
+                for ancestor in ancestors(proc) {
+                    *descendants.entry(ancestor.pid).or_default() += 1;
                 }
-            })
-        })
-    })
+            }
+            procs.sort_by_key(|proc| {
+                let n = descendants.get(&proc.pid).cloned().unwrap_or(0);
+                (Reverse(n), proc.starttime)
+            });
+        }
+    }
+}
+
+/// The processes that started this one, parent first, the init process is left out
+pub fn ancestors(proc: &Process) -> Vec<Ancestor> {
+    const MAX_DEPTH: usize = 64;
+    let table = TABLE.read().unwrap();
+    let mut ancestors = Vec::new();
+    let mut child = proc;
+    while child.ppid > 1 && ancestors.len() < MAX_DEPTH {
+        let parent = match table.procs.get(&child.ppid) {
+            // a parent is older than its child, or its PID has been reused since
+            Some(parent) if parent.starttime <= child.starttime => parent,
+            _ => break,
+        };
+        ancestors.push(Ancestor {
+            pid: parent.pid,
+            exe: parent.exe.clone(),
+        });
+        child = parent;
+    }
+    ancestors
+}
+
+/// Whether the process is still running, and its PID hasn't been reused, always true for
+/// the kernel
+pub fn is_alive(id: ProcId) -> bool {
+    if id.pid == 0 {
+        return true;
+    }
+    if WATCHING.load(Ordering::Acquire) {
+        return TABLE.read().unwrap().is_alive(id);
+    }
+    // the table is only updated on misses
+    let path: PathBuf = format!("{}{}/stat", PROC, id.pid).into();
+    read_stat(path).map_or(false, |(_, _, starttime)| starttime == id.starttime)
+}
+
+/// Subscribe to process events, so `/proc` is only scanned as a whole at startup or after
+/// events were lost. Without it, every cache miss scans all of `/proc`.
+pub fn watch() -> io::Result<()> {
+    let mut connector = ProcConnector::new()?;
+    // after subscribing, so nothing is missed in between
+    TABLE.write().unwrap().start_scan();
+    let procs = scan_all();
+    TABLE.write().unwrap().finish_scan(procs);
+    WATCHING.store(true, Ordering::Release);
+    thread::spawn(move || loop {
+        match connector.recv() {
+            Ok(events) => TABLE.write().unwrap().apply(events),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                TABLE.write().unwrap().stale = true;
+            }
+            Err(e) => {
+                eprintln!("ERROR: proc connector: {}", e);
+                WATCHING.store(false, Ordering::Release);
+                return;
+            }
+        }
+    });
+    Ok(())
 }
 
 // http://manpages.ubuntu.com/manpages/bionic/en/man5/proc.5.html
 fn parse_proc_pid(mut path: PathBuf, pid: usize) -> Result<Process, io::Error> {
+    path.push("status");
+    let (uid, gid) = read_ids(&path)?;
+    path.pop();
     path.push("fd");
-    let mut inodes = Vec::new();
-    for file in fs::read_dir(&path)? {
-        let path = fs::read_link(file?.path())?;
-        let path = path.to_str().expect("symlink not a vaild UTF-8");
-        if path.starts_with("socket:[") && path.ends_with("]") {
-            let inode = path[8..path.len() - 1]
-                .parse::<Inode>()
-                .expect("inode not a number");
-            inodes.push(inode);
-        }
-    }
+    let inodes = read_inodes(&path)?;
     path.pop();
-    path.push("exe");
-    let exe = fs::read_link(&path)
+    let (exe, exe_deleted) = read_exe(&mut path, pid);
+    path.push("cmdline");
+    // empty for zombies
+    let cmdline = fs::read(&path)
         .unwrap_or_default()
-        .to_str()
-        .expect("symlink not a vaild UTF-8")
-        .to_owned();
+        .split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    path.pop();
+    path.push("cgroup");
+    // not mounted, or the process is a zombie
+    let cgroup = read_cgroup(&path).unwrap_or_default();
     path.pop();
+    let app_id = read_app_id(&mut path, &cgroup);
     path.push("stat");
-    let mut stat = File::open(path)?;
-    let mut buf = [0u8; 512];
-    let n = stat.read(&mut buf)?;
-    let stat = std::str::from_utf8(&buf[..n]).expect("stat not a vaild UTF-8");
-    // TODO: expect message
-    let mut iter = stat.rsplit(')').next().expect("").split(' ').skip(2);
-    let ppid = iter.next().expect("").parse().expect("");
-    let pgrp = iter.next().expect("").parse().expect("");
+    let (ppid, pgrp, starttime) = read_stat(path)?;
     Ok(Process {
         pid,
         ppid,
         pgrp,
+        starttime,
+        uid,
+        gid,
+        cgroup,
+        app_id,
         exe,
+        exe_deleted,
+        cmdline,
         inodes,
     })
 }
+
+/// Kernel threads have no executable, their path is empty
+fn read_exe(path: &mut PathBuf, pid: Pid) -> (String, bool) {
+    path.push("exe");
+    let link = fs::read_link(&path);
+    // the running file, even if it's no longer at the path
+    let running = fs::metadata(&path);
+    path.pop();
+    let mut link = match link {
+        Ok(link) => link.into_os_string().into_vec(),
+        Err(_) => return (String::new(), false),
+    };
+    let suffixed = link.ends_with(DELETED_SUFFIX);
+    if suffixed {
+        link.truncate(link.len() - DELETED_SUFFIX.len());
+    }
+    let link = PathBuf::from(OsString::from_vec(link));
+
+    // the link is resolved in the mount namespace of the process
+    let on_disk = if same_mount_ns(path) {
+        link.clone()
+    } else {
+        path.join("root")
+            .join(link.strip_prefix("/").unwrap_or(&link))
+    };
+    let exe_deleted = match (running, fs::metadata(&on_disk)) {
+        (Ok(running), Ok(on_disk)) => {
+            running.dev() != on_disk.dev() || running.ino() != on_disk.ino()
+        }
+        // not reachable from here, trust the kernel
+        (Ok(_), Err(_)) => suffixed,
+        (Err(_), _) => false,
+    };
+    let exe = appimage(path, pid, &link).unwrap_or_else(|| link.to_string_lossy().into_owned());
+    (exe, exe_deleted)
+}
+
+fn same_mount_ns(path: &mut PathBuf) -> bool {
+    lazy_static! {
+        static ref SELF: Option<u64> = fs::metadata("/proc/self/ns/mnt").ok().map(|m| m.ino());
+    }
+    path.push("ns/mnt");
+    let ns = fs::metadata(&path).ok().map(|m| m.ino());
+    path.pop();
+    path.pop();
+    ns.is_none() || ns == *SELF
+}
+
+/// The `.AppImage` file a process is running from, instead of the random directory it's
+/// mounted at, like `/tmp/.mount_AppNaMe1a2b3c/usr/bin/app`
+///
+/// `APPIMAGE` is only believed if the directory is a FUSE mount, served by a process running
+/// from that file. The runtime forks it before it runs the app, so it's a child of the
+/// process or of one of its ancestors.
+fn appimage(path: &mut PathBuf, pid: Pid, exe: &Path) -> Option<String> {
+    let mut mount = PathBuf::new();
+    let mounted = exe.components().any(|c| {
+        mount.push(c);
+        c.as_os_str().as_bytes().starts_with(b".mount_")
+    });
+    if !mounted {
+        return None;
+    }
+    path.push("environ");
+    let environ = fs::read(&path);
+    path.pop();
+    let environ = environ.ok()?;
+    // set by the AppImage runtime, inherited by the processes it starts
+    let var = |name: &[u8]| {
+        environ
+            .split(|&b| b == 0)
+            .find(|var| var.starts_with(name) && var.get(name.len()) == Some(&b'='))
+            .map(|var| &var[name.len() + 1..])
+    };
+    let appdir = Path::new(OsStr::from_bytes(var(b"APPDIR")?));
+    if !exe.starts_with(appdir) {
+        return None;
+    }
+    let appimage = Path::new(OsStr::from_bytes(var(b"APPIMAGE")?));
+    if !is_fuse_mount(path, &mount) {
+        return None;
+    }
+    // in the mount namespace of the process
+    let file = fs::metadata(path.join("root").join(appimage.strip_prefix("/").ok()?)).ok()?;
+    if !has_fuse_server(pid, (file.dev(), file.ino())) {
+        return None;
+    }
+    Some(appimage.to_string_lossy().into_owned())
+}
+
+/// Whether `dir` is a FUSE mount point in the mount namespace of the process
+fn is_fuse_mount(path: &mut PathBuf, dir: &Path) -> bool {
+    path.push("mountinfo");
+    let mountinfo = fs::read(&path);
+    path.pop();
+    let mountinfo = match mountinfo {
+        Ok(mountinfo) => mountinfo,
+        Err(_) => return false,
+    };
+    // id parent major:minor root mount-point options [optional...] - fstype source options
+    let fstype = mountinfo
+        .split(|&b| b == b'\n')
+        .filter_map(|line| {
+            let fields: Vec<&[u8]> = line.split(|&b| b == b' ').collect();
+            let separator = fields.iter().skip(6).position(|&field| field == b"-")? + 6;
+            if unescape_octal(fields[4]) == dir.as_os_str().as_bytes() {
+                fields.get(separator + 1).cloned()
+            } else {
+                None
+            }
+        })
+        // the last one hides the ones before
+        .last();
+    match fstype {
+        Some(fstype) => fstype == b"fuse" || fstype.starts_with(b"fuse."),
+        None => false,
+    }
+}
+
+/// Spaces, tabs, newlines and backslashes in `mountinfo` are escaped, like `\040`
+fn unescape_octal(field: &[u8]) -> Vec<u8> {
+    let octal = |b: &u8| (b'0'..=b'7').contains(b);
+    let mut unescaped = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        match field.get(i..i + 4) {
+            Some([b'\\', digits @ ..]) if digits.iter().all(octal) => {
+                let byte = digits
+                    .iter()
+                    .fold(0u32, |byte, d| byte * 8 + u32::from(d - b'0'));
+                unescaped.push(byte as u8);
+                i += 4;
+            }
+            _ => {
+                unescaped.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    unescaped
+}
+
+/// Whether a child of the process or of one of its ancestors runs from `file`, and has
+/// `/dev/fuse` open
+fn has_fuse_server(pid: Pid, file: (u64, u64)) -> bool {
+    let mut ancestor = pid;
+    for _ in 0..MAX_APPIMAGE_DEPTH {
+        // only the children of the main thread, the runtime doesn't start others
+        let children = format!("{}{}/task/{}/children", PROC, ancestor, ancestor);
+        let children = fs::read_to_string(children).unwrap_or_default();
+        for child in children.split_whitespace() {
+            let child = PathBuf::from(format!("{}{}", PROC, child));
+            let same_file =
+                fs::metadata(child.join("exe")).map_or(false, |exe| (exe.dev(), exe.ino()) == file);
+            if same_file && has_fuse_fd(&child.join("fd")) {
+                return true;
+            }
+        }
+        ancestor = match read_stat(format!("{}{}/stat", PROC, ancestor).into()) {
+            // not init, everything is below it
+            Ok((ppid, _, _)) if ppid > 1 => ppid,
+            _ => return false,
+        };
+    }
+    false
+}
+
+fn has_fuse_fd(path: &Path) -> bool {
+    let files = match fs::read_dir(path) {
+        Ok(files) => files,
+        Err(_) => return false,
+    };
+    files.filter_map(Result::ok).any(|file| {
+        fs::read_link(file.path()).map_or(false, |target| target == Path::new("/dev/fuse"))
+    })
+}
+
+/// `flatpak:<app-id>`, `snap:<name>`, `docker:<container-id>` or `podman:<container-id>`
+///
+/// Units below `user@<uid>.service` are named by the user. A snap app there is only
+/// believed if it is confined by snapd or runs from the snap. Nothing tells the user's own
+/// containers apart, rules for rootless containers should also match the user.
+///
+/// A flatpak app is only believed if the scope flatpak runs it in and `.flatpak-info` agree.
+/// The user can fake both from outside the sandbox, but an app can't name scopes or change
+/// `.flatpak-info` from inside it, so it can't pass for another one.
+fn read_app_id(path: &mut PathBuf, cgroup: &str) -> Option<String> {
+    if let Some((id, named_by_user)) = cgroup_app_id(cgroup) {
+        let snap = id.starts_with("snap:");
+        if named_by_user && snap && !is_snap(path, &id["snap:".len()..]) {
+            return None;
+        }
+        return Some(id);
+    }
+    let scope = flatpak_scope_id(cgroup)?;
+    // in the mount namespace of the sandbox
+    path.push("root/.flatpak-info");
+    let info = fs::read_to_string(&path);
+    path.pop();
+    path.pop();
+    let info = info.ok()?;
+    // [Application]
+    // name=org.mozilla.firefox
+    let mut in_application = false;
+    for line in info.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_application = line == "[Application]";
+        } else if in_application && line.starts_with("name=") {
+            let name = &line["name=".len()..];
+            if name != scope {
+                return None;
+            }
+            return Some(format!("flatpak:{}", name));
+        }
+    }
+    None
+}
+
+/// Whether the process is in the mount namespace snapd made for the snap, or runs a file of
+/// it, a strictly confined app may run one of its base snap
+fn is_snap(path: &Path, name: &str) -> bool {
+    let id = |path: &Path| fs::metadata(path).ok().map(|file| (file.dev(), file.ino()));
+    let mount_ns = id(&path.join("ns/mnt"));
+    if mount_ns.is_some() && mount_ns == id(Path::new(&format!("/run/snapd/ns/{}.mnt", name))) {
+        return true;
+    }
+    // classic confinement has no namespace of its own
+    match (
+        fs::metadata(path.join("exe")),
+        fs::metadata(format!("/snap/{}/current", name)),
+    ) {
+        (Ok(exe), Ok(snap)) => exe.dev() == snap.dev(),
+        _ => false,
+    }
+}
+
+/// The app ID of `app-flatpak-<app-id>-<pid>.scope`
+fn flatpak_scope_id(cgroup: &str) -> Option<String> {
+    const PREFIX: &str = "app-flatpak-";
+    const SUFFIX: &str = ".scope";
+    cgroup.split('/').find_map(|unit| {
+        if !unit.starts_with(PREFIX)
+            || !unit.ends_with(SUFFIX)
+            || unit.len() < PREFIX.len() + SUFFIX.len()
+        {
+            return None;
+        }
+        let mut parts = unit[PREFIX.len()..unit.len() - SUFFIX.len()].rsplitn(2, '-');
+        let pid = parts.next()?;
+        let id = parts.next()?;
+        if pid.is_empty() || !pid.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        Some(unescape_unit(id))
+    })
+}
+
+/// Unit names escape `-` and other bytes, like `\x2d`
+fn unescape_unit(name: &str) -> String {
+    let name = name.as_bytes();
+    let mut unescaped = Vec::with_capacity(name.len());
+    let mut i = 0;
+    while i < name.len() {
+        let hex = name
+            .get(i..i + 4)
+            .filter(|escape| escape.starts_with(b"\\x"))
+            .and_then(|escape| std::str::from_utf8(&escape[2..]).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match hex {
+            Some(byte) => {
+                unescaped.push(byte);
+                i += 4;
+            }
+            None => {
+                unescaped.push(name[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+/// The app ID, and whether it's in a unit named by a user
+fn cgroup_app_id(cgroup: &str) -> Option<(String, bool)> {
+    let container = |runtime: &str, id: &str| {
+        if id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            Some(format!("{}:{}", runtime, id))
+        } else {
+            None
+        }
+    };
+    let mut parent = "";
+    let mut named_by_user = false;
+    // the outermost one, a container may have its own units inside
+    for unit in cgroup.split('/') {
+        // delegated to the user, who can name units anything
+        if unit.starts_with("user@") && unit.ends_with(".service") {
+            named_by_user = true;
+        }
+        let name = unit.trim_end_matches(".scope");
+        let id = if unit.starts_with("snap.") {
+            // snap.<name>.<app>-<uuid>.scope, snap.<name>.<app>.service
+            let name = unit["snap.".len()..].split('.').next().unwrap();
+            Some(format!("snap:{}", name))
+        } else if name.starts_with("docker-") {
+            // the systemd cgroup driver
+            container("docker", &name["docker-".len()..])
+        } else if name.starts_with("libpod-") {
+            // libpod-conmon-<id> is the monitor, not the container
+            container("podman", &name["libpod-".len()..])
+        } else if parent == "docker" {
+            // the cgroupfs driver
+            container("docker", unit)
+        } else {
+            None
+        };
+        if let Some(id) = id {
+            return Some((id, named_by_user));
+        }
+        parent = unit;
+    }
+    None
+}
+
+/// The path in the unified hierarchy, or in the one of systemd if cgroup v1 is used alone
+fn read_cgroup(path: &PathBuf) -> Result<String, io::Error> {
+    let content = fs::read_to_string(path)?;
+    let (mut unified, mut systemd) = (None, None);
+    // hierarchy-ID:controller-list:cgroup-path
+    for line in content.lines() {
+        let mut fields = line.splitn(3, ':');
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some("0"), Some(""), Some(path)) => unified = Some(path),
+            (Some(_), Some("name=systemd"), Some(path)) => systemd = Some(path),
+            _ => (),
+        }
+    }
+    // the unified hierarchy of a hybrid setup is empty unless systemd uses it
+    let path = match (unified, systemd) {
+        (Some("/"), Some(path)) | (None, Some(path)) => path,
+        (Some(path), _) => path,
+        (None, None) => return Err(io::Error::new(io::ErrorKind::InvalidData, "no cgroup")),
+    };
+    Ok(path.to_owned())
+}
+
+/// The effective user and group IDs, `/proc/<pid>` itself is owned by root if the process is
+/// not dumpable
+fn read_ids(path: &Path) -> Result<(u32, u32), io::Error> {
+    let status = fs::read_to_string(path)?;
+    // real, effective, saved set and filesystem
+    let effective = |key: &str| {
+        status
+            .lines()
+            .find(|line| line.starts_with(key))
+            .and_then(|line| line[key.len()..].split_whitespace().nth(1))
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid status"))
+    };
+    Ok((effective("Uid:")?, effective("Gid:")?))
+}
+
+/// Sockets in the `fd` directory of a process
+fn read_inodes(path: &Path) -> Result<Vec<Inode>, io::Error> {
+    let mut inodes = Vec::new();
+    for file in fs::read_dir(path)? {
+        // closed in the meantime
+        let target = match fs::read_link(file?.path()) {
+            Ok(target) => target.into_os_string().into_vec(),
+            Err(_) => continue,
+        };
+        if target.starts_with(b"socket:[") && target.ends_with(b"]") {
+            let inode = std::str::from_utf8(&target[8..target.len() - 1])
+                .ok()
+                .and_then(|inode| inode.parse::<Inode>().ok());
+            inodes.extend(inode);
+        }
+    }
+    Ok(inodes)
+}
+
+/// Returns ppid, pgrp and starttime
+fn read_stat(path: PathBuf) -> Result<(Pid, Pid, u64), io::Error> {
+    let mut stat = File::open(path)?;
+    let mut buf = [0u8; 512];
+    let n = stat.read(&mut buf)?;
+    // the name of the executable may be anything
+    let stat = String::from_utf8_lossy(&buf[..n]);
+    // the fields after `(comm)` start from the 3rd one
+    let fields: Vec<&str> = stat.rsplit(')').next().expect("").split(' ').collect();
+    let field = |n: usize| {
+        fields
+            .get(n - 2)
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid stat"))
+    };
+    Ok((field(4)? as Pid, field(5)? as Pid, field(22)?))
+}
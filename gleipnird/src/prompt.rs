@@ -0,0 +1,169 @@
+//! Connections held until a client answers, see `RuleTarget::Ask`
+//!
+//! A verdict must be issued on the queue the packet came from, so held packets stay in
+//! their worker. `Prompter` is shared by all workers, it sends prompts to the RPC server
+//! and routes answers back to the worker waiting for them. Once a prompt is answered, its
+//! packets go through the worker again and get the answer like any later packet of the flow.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{self, Receiver, Sender};
+use gleipnir_interface::ConnectionPrompt;
+use lru_time_cache::LruCache;
+use nfq;
+
+/// Packets of a flow held at most, the rest are dropped and retransmitted later
+const MAX_HELD: usize = 64;
+
+type Answer = (u64, bool);
+
+pub struct Prompter {
+    next_id: AtomicU64,
+    prompts: Sender<ConnectionPrompt>,
+    waiting: Mutex<HashMap<u64, (ConnectionPrompt, Sender<Answer>)>>,
+}
+
+impl Prompter {
+    pub fn new() -> (Self, Receiver<ConnectionPrompt>) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let prompter = Prompter {
+            next_id: AtomicU64::new(1),
+            prompts: sender,
+            waiting: Default::default(),
+        };
+        (prompter, receiver)
+    }
+
+    fn ask(&self, mut prompt: ConnectionPrompt, worker: &Sender<Answer>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        prompt.id = id;
+        self.waiting
+            .lock()
+            .unwrap()
+            .insert(id, (prompt.clone(), worker.clone()));
+        self.prompts.send(prompt).expect("prompt service dead");
+        id
+    }
+
+    /// Returns the prompt if it's still waiting for an answer
+    pub fn answer(&self, id: u64, accept: bool) -> Option<ConnectionPrompt> {
+        let (prompt, worker) = self.waiting.lock().unwrap().remove(&id)?;
+        worker.send((id, accept)).expect("worker dead");
+        Some(prompt)
+    }
+
+    /// Returns false if it has been answered, the answer is on its way to the worker
+    fn forget(&self, id: u64) -> bool {
+        self.waiting.lock().unwrap().remove(&id).is_some()
+    }
+}
+
+/// A packet that needs an answer
+pub struct Request {
+    /// Packets of the same flow share a prompt
+    pub flow: u64,
+    pub prompt: ConnectionPrompt,
+    pub timeout: Duration,
+    /// The verdict when nobody answers in time
+    pub fallback: bool,
+}
+
+struct Held {
+    flow: u64,
+    packets: Vec<nfq::Message>,
+    deadline: Instant,
+    fallback: bool,
+}
+
+/// Prompts of a worker
+pub struct Pending {
+    prompter: Arc<Prompter>,
+    sender: Sender<Answer>,
+    answers: Receiver<Answer>,
+    /// Flow to prompt id
+    flows: HashMap<u64, u64>,
+    held: HashMap<u64, Held>,
+    /// So the packets after an answer don't ask again
+    answered: LruCache<u64, bool>,
+}
+
+impl Pending {
+    pub fn new(prompter: Arc<Prompter>) -> Self {
+        let (sender, answers) = crossbeam_channel::unbounded();
+        Pending {
+            prompter,
+            sender,
+            answers,
+            flows: HashMap::new(),
+            held: HashMap::new(),
+            answered: LruCache::with_capacity(2048),
+        }
+    }
+
+    pub fn answered(&mut self, flow: u64) -> Option<bool> {
+        self.answered.get(&flow).cloned()
+    }
+
+    /// Only the first packet of a flow starts a prompt, returns the packet back if too many
+    /// packets of the flow are held
+    pub fn hold(&mut self, request: Request, msg: nfq::Message) -> Option<nfq::Message> {
+        if let Some(id) = self.flows.get(&request.flow) {
+            let held = self.held.get_mut(id).expect("flow without prompt");
+            if held.packets.len() >= MAX_HELD {
+                return Some(msg);
+            }
+            held.packets.push(msg);
+            return None;
+        }
+        let id = self.prompter.ask(request.prompt, &self.sender);
+        self.flows.insert(request.flow, id);
+        self.held.insert(
+            id,
+            Held {
+                flow: request.flow,
+                packets: vec![msg],
+                deadline: Instant::now() + request.timeout,
+                fallback: request.fallback,
+            },
+        );
+        None
+    }
+
+    /// Packets whose prompt has been answered or has timed out, `answered` has the verdict
+    pub fn resolve(&mut self) -> Vec<nfq::Message> {
+        let mut resolved = Vec::new();
+        if self.held.is_empty() {
+            return resolved;
+        }
+        let answers: Vec<_> = self.answers.try_iter().collect();
+        for (id, accept) in answers {
+            self.finish(id, accept, &mut resolved);
+        }
+        let now = Instant::now();
+        let expired: Vec<_> = self
+            .held
+            .iter()
+            .filter(|(_, held)| held.deadline <= now)
+            .map(|(id, held)| (*id, held.fallback))
+            .collect();
+        for (id, fallback) in expired {
+            if self.prompter.forget(id) {
+                self.finish(id, fallback, &mut resolved);
+            }
+        }
+        resolved
+    }
+
+    fn finish(&mut self, id: u64, accept: bool, resolved: &mut Vec<nfq::Message>) {
+        let held = match self.held.remove(&id) {
+            Some(held) => held,
+            None => return,
+        };
+        self.flows.remove(&held.flow);
+        self.answered.insert(held.flow, accept);
+        resolved.extend(held.packets);
+    }
+}
@@ -8,7 +8,10 @@ use std::thread;
 use crossbeam_channel;
 use futures::{compat::Future01CompatExt, executor::block_on, prelude::*};
 use futures_locks::Mutex;
-use gleipnir_interface::{self, unixtransport, Daemon, PackageReport, Rules};
+use gleipnir_interface::{
+    self, unixtransport, ConnectionPrompt, Daemon, ExeAlert, PackageReport, PromptAnswer, Remember,
+    Rule, RuleTarget, Rules,
+};
 use slab::Slab;
 use tarpc::rpc::context::Context;
 use tarpc::server::Channel;
@@ -16,8 +19,11 @@ use tokio::task::block_in_place;
 use tokio_serde::formats::Bincode;
 
 use crate::config;
+use crate::ebpf;
 use crate::lrlock::Setter;
-use crate::rules::IndexedRules;
+use crate::netfilter;
+use crate::prompt::Prompter;
+use crate::rules::{self, IndexedRules};
 
 #[derive(Clone)]
 struct MyDaemon {
@@ -27,6 +33,90 @@ struct MyDaemon {
     rules: Arc<Mutex<Rules>>,
     clients: Arc<Mutex<Slab<gleipnir_interface::MonitorClient>>>,
     client_id: Arc<Mutex<Option<usize>>>,
+    verdicts: Option<Arc<ebpf::Verdicts>>,
+    registration: Option<Arc<netfilter::Registration>>,
+    prompter: Arc<Prompter>,
+}
+
+impl MyDaemon {
+    /// Edit, apply and save the rules, then send them to other clients,
+    /// or to every client if `notify_self` is set
+    ///
+    /// The rules stay locked until they are saved, so concurrent edits are not lost.
+    async fn update_rules(self, edit: impl FnOnce(&mut Rules), notify_self: bool) {
+        let mut current = self.rules.lock().compat().await.unwrap();
+        edit(&mut current);
+        let rules = current.clone();
+        let indexed_rules = IndexedRules::from(rules.clone());
+        let generation = indexed_rules.generation();
+        let rules_setter = self.rules_setter.lock().compat().await.unwrap();
+        rules_setter.set(indexed_rules);
+        // after the swap, or a worker could write back a verdict of the old rules
+        if let Some(verdicts) = &self.verdicts {
+            block_in_place(|| verdicts.refresh(&rules_setter.read()));
+        }
+        drop(rules_setter);
+        if let Some(registration) = &self.registration {
+            if let Err(e) = block_in_place(|| registration.set_generation(generation)) {
+                eprintln!("ERROR: verdicts of the old rules are still cached: {}", e);
+            }
+        }
+        config::save_rules(&rules);
+        drop(current);
+        let boardcast = async move {
+            let self_id = *self.client_id.lock().compat().await.unwrap();
+            let skip = if notify_self { None } else { self_id };
+            for (id, mut client) in connected(&self.clients).await {
+                if Some(id) == skip {
+                    continue;
+                }
+                if let Err(e) = client
+                    .on_rules_updated(tarpc::context::current(), rules.clone())
+                    .await
+                {
+                    // TODO: remove client from clients?
+                    dbg!(e);
+                }
+            }
+        };
+        tokio::spawn(boardcast);
+    }
+}
+
+/// The clients connected now, the lock is not held while they are waited for
+async fn connected(
+    clients: &Mutex<Slab<gleipnir_interface::MonitorClient>>,
+) -> Vec<(usize, gleipnir_interface::MonitorClient)> {
+    let clients = clients.lock().compat().await.unwrap();
+    clients
+        .iter()
+        .map(|(id, client)| (id, client.clone()))
+        .collect()
+}
+
+/// A rule that matches the prompted connection
+fn prompt_rule(prompt: &ConnectionPrompt, accept: bool, temporary: bool) -> Rule {
+    let ip = prompt.addr.ip();
+    let port = prompt.addr.port();
+    Rule {
+        device: Some(prompt.device),
+        proto: Some(prompt.protocol),
+        exe: Some(prompt.exe.clone()),
+        // the remote port of an incoming connection is random
+        port: if prompt.device.is_input() || prompt.protocol.is_icmp() {
+            None
+        } else {
+            Some(port..=port)
+        },
+        subnet: Some((ip, if ip.is_ipv4() { 32 } else { 128 })),
+        target: if accept {
+            RuleTarget::Accept
+        } else {
+            RuleTarget::Drop
+        },
+        temporary,
+        ..Default::default()
+    }
 }
 
 impl Drop for MyDaemon {
@@ -48,42 +138,19 @@ impl Drop for MyDaemon {
 }
 
 impl gleipnir_interface::Daemon for MyDaemon {
-    type SetRulesFut = impl Future<Output = ()>;
+    type SetRulesFut = impl Future<Output = Result<(), String>>;
     type UnlockFut = impl Future<Output = bool>;
     type InitMonitorFut = impl Future<Output = ()>;
+    type AnswerPromptFut = impl Future<Output = ()>;
 
     fn set_rules(self, _: Context, rules: Rules) -> Self::SetRulesFut {
         async move {
             if self.authenticated.load(Ordering::Relaxed) {
-                let indexed_rules = IndexedRules::from(rules.clone());
-                self.rules_setter
-                    .lock()
-                    .compat()
-                    .await
-                    .unwrap()
-                    .set(indexed_rules);
-                config::save_rules(&rules);
-                *self.rules.lock().compat().await.unwrap() = rules.clone();
-                let boardcast = async move {
-                    let self_id = self.client_id.lock().compat().await.unwrap();
-                    if let Some(self_id) = &*self_id {
-                        let mut clients = self.clients.lock().compat().await.unwrap();
-                        for (id, client) in clients.iter_mut() {
-                            if id == *self_id {
-                                continue;
-                            }
-                            if let Err(e) = client
-                                .on_rules_updated(tarpc::context::current(), rules.clone())
-                                .await
-                            {
-                                // TODO: remove client from clients?
-                                dbg!(e);
-                            }
-                        }
-                    }
-                };
-                tokio::spawn(boardcast);
+                rules::validate(&rules)?;
+                self.update_rules(move |current| *current = rules, false)
+                    .await;
             }
+            Ok(())
         }
     }
     fn unlock(self, _: Context) -> Self::UnlockFut {
@@ -122,12 +189,38 @@ impl gleipnir_interface::Daemon for MyDaemon {
             }
         }
     }
+    fn answer_prompt(self, _: Context, id: u64, answer: PromptAnswer) -> Self::AnswerPromptFut {
+        async move {
+            if !self.authenticated.load(Ordering::Relaxed) {
+                return;
+            }
+            let prompt = match self.prompter.answer(id, answer.accept) {
+                Some(prompt) => prompt,
+                None => return,
+            };
+            let temporary = match answer.remember {
+                Remember::Once => return,
+                Remember::Temporary => true,
+                Remember::Forever => false,
+            };
+            let rule = prompt_rule(&prompt, answer.accept, temporary);
+            // the client doesn't have the new rule
+            self.update_rules(move |rules| rules.rules.insert(0, rule), true)
+                .await;
+        }
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     rules: Rules,
     rules_setter: Setter<IndexedRules>,
     pkt_logs: crossbeam_channel::Receiver<PackageReport>,
+    verdicts: Option<Arc<ebpf::Verdicts>>,
+    registration: Option<Arc<netfilter::Registration>>,
+    prompter: Arc<Prompter>,
+    prompts: crossbeam_channel::Receiver<ConnectionPrompt>,
+    alerts: crossbeam_channel::Receiver<ExeAlert>,
 ) -> Result<(), std::io::Error> {
     let addr = std::path::PathBuf::from("/var/run/gleipnird");
     if addr.exists() {
@@ -144,6 +237,8 @@ pub fn run(
     let clients: Arc<Mutex<Slab<gleipnir_interface::MonitorClient>>> =
         Arc::new(Mutex::new(Slab::new()));
     let clients2 = clients.clone();
+    let clients3 = clients.clone();
+    let clients4 = clients.clone();
 
     let mut runtime = tokio::runtime::Runtime::new().expect("tokio runtime");
 
@@ -167,6 +262,9 @@ pub fn run(
                     rules: rules.clone(),
                     clients: clients.clone(),
                     client_id: Arc::new(Mutex::new(None)),
+                    verdicts: verdicts.clone(),
+                    registration: registration.clone(),
+                    prompter: prompter.clone(),
                 };
                 channel.respond_with(server.serve()).execute()
             })
@@ -182,7 +280,7 @@ pub fn run(
         logs.extend(pkt_logs.try_iter());
         let clients = clients2.clone();
         let fut = async move {
-            for (_id, client) in clients.lock().compat().await.unwrap().iter_mut() {
+            for (_id, mut client) in connected(&clients).await {
                 let r = client
                     .on_packages(tarpc::context::current(), logs.clone())
                     .await;
@@ -194,5 +292,42 @@ pub fn run(
         handle.spawn(fut);
     });
 
+    let handle = runtime.handle().clone();
+
+    thread::spawn(move || loop {
+        let prompt = prompts.recv().expect("prompts disconnected");
+        let clients = clients3.clone();
+        // unanswered prompts time out in the workers
+        let fut = async move {
+            for (_id, mut client) in connected(&clients).await {
+                let r = client
+                    .on_connection_prompt(tarpc::context::current(), prompt.clone())
+                    .await;
+                if let Err(e) = r {
+                    dbg!(e);
+                }
+            }
+        };
+        handle.spawn(fut);
+    });
+
+    let handle = runtime.handle().clone();
+
+    thread::spawn(move || loop {
+        let alert = alerts.recv().expect("alerts disconnected");
+        let clients = clients4.clone();
+        let fut = async move {
+            for (_id, mut client) in connected(&clients).await {
+                let r = client
+                    .on_exe_alert(tarpc::context::current(), alert.clone())
+                    .await;
+                if let Err(e) = r {
+                    dbg!(e);
+                }
+            }
+        };
+        handle.spawn(fut);
+    });
+
     runtime.block_on(server)
 }
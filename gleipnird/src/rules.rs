@@ -1,16 +1,29 @@
-use std::cell::RefCell;
+use std::cmp::Reverse;
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::iter::FromIterator;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
 use intervaltree::IntervalTree;
 use lru_time_cache::LruCache;
+use regex::Regex;
 use treebitmap::IpLookupTable;
 
-use gleipnir_interface::{Address, Device, Proto, Rule, RuleTarget, Rules};
+use gleipnir_interface::{
+    is_interpreter, Address, Ancestor, Device, ExeMatch, OwnerPolicy, PacketInfo, Proto, Rule,
+    RuleTarget, Rules, UnknownPolicy,
+};
+
+use crate::netfilter::GENERATION_MASK;
+
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+
+/// The match cache is split into shards, so workers rarely wait for each other
+const CACHE_SHARDS: usize = 16;
 
 struct Bucket {
     bytes: usize,
@@ -46,27 +59,157 @@ impl Bucket {
     }
 }
 
+/// Rules keyed by the subnet of one side of the packet, see `Rule::subnet`
+struct Subnets {
+    v4_table: IpLookupTable<Ipv4Addr, Vec<usize>>,
+    any_v4: Vec<usize>,
+    v6_table: IpLookupTable<Ipv6Addr, Vec<usize>>,
+    any_v6: Vec<usize>,
+}
+
+impl Subnets {
+    /// The subnet of each rule, in order
+    fn new(subnets: impl Iterator<Item = Option<(IpAddr, u8)>>) -> Self {
+        let mut v4_hashmap: HashMap<(Ipv4Addr, u8), Vec<usize>> = HashMap::new();
+        let mut v6_hashmap: HashMap<(Ipv6Addr, u8), Vec<usize>> = HashMap::new();
+        let mut any_v4 = Vec::new();
+        let mut any_v6 = Vec::new();
+        for (index, subnet) in subnets.enumerate() {
+            match subnet {
+                Some((IpAddr::V4(subnet), mask)) => {
+                    v4_hashmap
+                        .entry((subnet.mask(mask), mask))
+                        .or_default()
+                        .push(index);
+                }
+                Some((IpAddr::V6(subnet), mask)) => {
+                    v6_hashmap
+                        .entry((subnet.mask(mask), mask))
+                        .or_default()
+                        .push(index);
+                }
+                None => {
+                    any_v4.push(index);
+                    any_v6.push(index);
+                }
+            }
+        }
+
+        let mut v4_table = IpLookupTable::new();
+        for (ip, masklen, index) in Self::nest(&v4_hashmap) {
+            v4_table.insert(ip, masklen.into(), index);
+        }
+        let mut v6_table = IpLookupTable::new();
+        for (ip, masklen, index) in Self::nest(&v6_hashmap) {
+            v6_table.insert(ip, masklen.into(), index);
+        }
+        Subnets {
+            v4_table,
+            any_v4,
+            v6_table,
+            any_v6,
+        }
+    }
+
+    /// Adds the rules of the subnets containing each subnet, so the longest match has all of
+    /// the matching rules, like both `10.0.0.0/8` and `10.1.0.0/16` for `10.1.2.3`
+    fn nest<A: Address + Eq + Hash>(
+        subnets: &HashMap<(A, u8), Vec<usize>>,
+    ) -> Vec<(A, u8, Vec<usize>)> {
+        subnets
+            .keys()
+            .map(|&(ip, masklen)| {
+                let mut indices: Vec<usize> = subnets
+                    .iter()
+                    .filter(|((subnet, len), _)| *len <= masklen && ip.mask(*len) == *subnet)
+                    .flat_map(|(_, indices)| indices.iter().cloned())
+                    .collect();
+                indices.sort_unstable();
+                (ip, masklen, indices)
+            })
+            .collect()
+    }
+
+    /// Rules of every matching subnet, and the ones matching any address
+    fn candidates(&self, ip: IpAddr) -> (Option<&Vec<usize>>, &Vec<usize>) {
+        match ip {
+            IpAddr::V4(ip) => (self.v4_table.longest_match(ip).map(|x| x.2), &self.any_v4),
+            IpAddr::V6(ip) => (self.v6_table.longest_match(ip).map(|x| x.2), &self.any_v6),
+        }
+    }
+}
+
+/// Rules by the value of each field, the ones without it or with a pattern are in `any_<field>`
 pub struct IndexedRules {
     device: HashMap<Device, Vec<usize>>,
     any_device: Vec<usize>,
     proto: HashMap<Proto, Vec<usize>>,
     any_proto: Vec<usize>,
+    interface: HashMap<String, Vec<usize>>,
+    any_interface: Vec<usize>,
     exe: HashMap<String, Vec<usize>>,
     any_exe: Vec<usize>,
-    v4_table: IpLookupTable<Ipv4Addr, Vec<usize>>,
-    any_v4: Vec<usize>,
-    v6_table: IpLookupTable<Ipv6Addr, Vec<usize>>,
-    any_v6: Vec<usize>,
+    /// Compiled `ExeMatch::Glob` and `ExeMatch::Regex` patterns, these rules are in `any_exe`
+    exe_patterns: HashMap<usize, Regex>,
+    /// Rules in `exe` that match descendants too
+    inherited: HashMap<String, Vec<usize>>,
+    needs_ancestors: bool,
+    uid: HashMap<u32, Vec<usize>>,
+    any_uid: Vec<usize>,
+    gid: HashMap<u32, Vec<usize>>,
+    any_gid: Vec<usize>,
+    /// Keyed by cgroup paths and unit names
+    cgroup: HashMap<String, Vec<usize>>,
+    any_cgroup: Vec<usize>,
+    app_id: HashMap<String, Vec<usize>>,
+    any_app_id: Vec<usize>,
+    subnet: Subnets,
+    local_subnet: Subnets,
     port: IntervalTree<u16, usize>,
     any_port: Vec<usize>,
+    local_port: IntervalTree<u16, usize>,
+    any_local_port: Vec<usize>,
     raw: Vec<Rule>,
     default_target: RuleTarget,
-    rate_state: RefCell<Vec<Bucket>>,
-    cache: RefCell<LruCache<u64, (Option<usize>, RuleTarget)>>,
+    unknown_policy: UnknownPolicy,
+    unknown_rules: Vec<Rule>,
+    /// Like `exe_patterns`, for `unknown_rules`
+    unknown_patterns: HashMap<usize, Regex>,
+    ask_timeout: Duration,
+    ask_fallback: RuleTarget,
+    interpreters: Vec<String>,
+    hash_mismatch: RuleTarget,
+    owner_policy: OwnerPolicy,
+    needs_names: bool,
+    /// Whether some rule matches on them, they are left out of the cache key otherwise
+    needs_port: bool,
+    needs_local_ip: bool,
+    needs_local_port: bool,
+    /// Rules with `Rule::sha256`
+    pinned: Vec<usize>,
+    rate_state: Vec<Mutex<Bucket>>,
+    cache: Vec<Mutex<LruCache<u64, (Option<usize>, RuleTarget)>>>,
+    generation: u32,
 }
 
 impl IndexedRules {
     pub fn new(default_target: RuleTarget, rules: Vec<Rule>, rate_rules: Vec<usize>) -> Self {
+        Self::with_unknown(
+            default_target,
+            rules,
+            rate_rules,
+            UnknownPolicy::Accept,
+            Vec::new(),
+        )
+    }
+
+    pub fn with_unknown(
+        default_target: RuleTarget,
+        rules: Vec<Rule>,
+        rate_rules: Vec<usize>,
+        unknown_policy: UnknownPolicy,
+        unknown_rules: Vec<Rule>,
+    ) -> Self {
         macro_rules! insert_rule {
             ($target: tt, $rule: tt, $name: tt, $any: tt,  $index: tt) => {
                 if let Some(k) = $rule.$name {
@@ -77,141 +220,363 @@ impl IndexedRules {
             };
         }
 
+        let needs_names = rules
+            .iter()
+            .chain(&unknown_rules)
+            .any(|rule| rule.domain.is_some());
+        // the rest is set by `From<Rules>`
+        let defaults = Rules::default();
         let mut r = Self {
             device: Default::default(),
             any_device: Default::default(),
             proto: Default::default(),
             any_proto: Default::default(),
+            interface: Default::default(),
+            any_interface: Default::default(),
             exe: Default::default(),
             any_exe: Default::default(),
-            v4_table: IpLookupTable::new(),
-            any_v4: Default::default(),
-            v6_table: IpLookupTable::new(),
-            any_v6: Default::default(),
+            exe_patterns: Default::default(),
+            inherited: Default::default(),
+            needs_ancestors: false,
+            uid: Default::default(),
+            any_uid: Default::default(),
+            gid: Default::default(),
+            any_gid: Default::default(),
+            cgroup: Default::default(),
+            any_cgroup: Default::default(),
+            app_id: Default::default(),
+            any_app_id: Default::default(),
+            subnet: Subnets::new(rules.iter().map(|rule| rule.subnet)),
+            local_subnet: Subnets::new(rules.iter().map(|rule| rule.local_subnet)),
             port: IntervalTree::from_iter(vec![(0..0, 0); 0].into_iter()),
             any_port: Default::default(),
+            local_port: IntervalTree::from_iter(vec![(0..0, 0); 0].into_iter()),
+            any_local_port: Default::default(),
             raw: rules.clone(),
             default_target: default_target,
-            rate_state: Default::default(),
-            cache: RefCell::new(LruCache::with_capacity(2048)),
+            unknown_policy,
+            unknown_patterns: unknown_rules
+                .iter()
+                .enumerate()
+                .filter_map(|(index, rule)| Some((index, rule.exe_regex()?.ok()?)))
+                .collect(),
+            unknown_rules,
+            ask_timeout: Duration::from_secs(defaults.ask_timeout.into()),
+            ask_fallback: defaults.ask_fallback,
+            interpreters: defaults.interpreters,
+            hash_mismatch: defaults.hash_mismatch,
+            owner_policy: defaults.owner_policy,
+            needs_names,
+            needs_port: rules.iter().any(|rule| rule.port.is_some()),
+            needs_local_ip: rules.iter().any(|rule| rule.local_subnet.is_some()),
+            needs_local_port: rules.iter().any(|rule| rule.local_port.is_some()),
+            pinned: rules
+                .iter()
+                .enumerate()
+                .filter(|(_, rule)| rule.sha256.is_some())
+                .map(|(index, _)| index)
+                .collect(),
+            rate_state: rate_rules
+                .into_iter()
+                .map(|limit| Mutex::new(Bucket::new(limit)))
+                .collect(),
+            cache: (0..CACHE_SHARDS)
+                .map(|_| Mutex::new(LruCache::with_capacity(2048 / CACHE_SHARDS)))
+                .collect(),
+            generation: GENERATION.fetch_add(1, Ordering::Relaxed) & GENERATION_MASK,
         };
 
-        for limit in rate_rules {
-            r.rate_state.borrow_mut().push(Bucket::new(limit));
-        }
-
-        let mut v4_hashmap: HashMap<(Ipv4Addr, u8), Vec<usize>> = HashMap::new();
-        let mut v6_hashmap: HashMap<(Ipv6Addr, u8), Vec<usize>> = HashMap::new();
-
         let mut port_rules = Vec::new();
+        let mut local_port_rules = Vec::new();
 
         for (index, rule) in rules.into_iter().enumerate() {
             insert_rule!(r, rule, device, any_device, index);
             insert_rule!(r, rule, proto, any_proto, index);
-            insert_rule!(r, rule, exe, any_exe, index);
+            match rule.interface {
+                Some(pattern) if !pattern.contains('*') => {
+                    r.interface.entry(pattern).or_default().push(index)
+                }
+                _ => r.any_interface.push(index),
+            }
+            if rule.descendants && rule.exe.is_some() {
+                r.needs_ancestors = true;
+            }
+            match rule.exe_regex() {
+                None => {
+                    if let (Some(exe), true) = (&rule.exe, rule.descendants) {
+                        r.inherited.entry(exe.clone()).or_default().push(index);
+                    }
+                    insert_rule!(r, rule, exe, any_exe, index);
+                }
+                Some(Ok(pattern)) => {
+                    r.exe_patterns.insert(index, pattern);
+                    r.any_exe.push(index);
+                }
+                // matches nothing
+                Some(Err(e)) => {
+                    eprintln!("WARNING: rule {}: invalid exe pattern: {}", index, e);
+                    r.any_exe.push(index);
+                }
+            }
+            insert_rule!(r, rule, uid, any_uid, index);
+            insert_rule!(r, rule, gid, any_gid, index);
+            match rule.cgroup {
+                Some(pattern) if !pattern.contains('*') => {
+                    let key = if pattern.starts_with('/') {
+                        pattern.trim_end_matches('/')
+                    } else {
+                        &pattern
+                    };
+                    r.cgroup.entry(key.to_owned()).or_default().push(index);
+                }
+                _ => r.any_cgroup.push(index),
+            }
+            match rule.app_id {
+                Some(pattern) if !pattern.contains('*') => {
+                    r.app_id.entry(pattern).or_default().push(index)
+                }
+                _ => r.any_app_id.push(index),
+            }
             if let Some(port_range) = rule.port {
                 let (start, end) = port_range.into_inner();
                 port_rules.push((start..end + 1, index));
             } else {
                 r.any_port.push(index);
             }
-            match rule.subnet {
-                Some((IpAddr::V4(subnet), mask)) => {
-                    v4_hashmap
-                        .entry((subnet.mask(mask), mask))
-                        .or_default()
-                        .push(index);
-                }
-                Some((IpAddr::V6(subnet), mask)) => {
-                    v6_hashmap
-                        .entry((subnet.mask(mask), mask))
-                        .or_default()
-                        .push(index);
-                }
-                None => {
-                    r.any_v4.push(index);
-                    r.any_v6.push(index);
-                }
+            if let Some(port_range) = rule.local_port {
+                let (start, end) = port_range.into_inner();
+                local_port_rules.push((start..end + 1, index));
+            } else {
+                r.any_local_port.push(index);
             }
         }
 
         r.port = IntervalTree::from_iter(port_rules);
+        r.local_port = IntervalTree::from_iter(local_port_rules);
+
+        r
+    }
+
+    /// Every `IndexedRules` gets a different generation, until it wraps around
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
 
-        for ((ip, masklen), index) in v4_hashmap {
-            r.v4_table.insert(ip, masklen.into(), index);
+    /// Host names are only looked up if some rules match them
+    pub fn needs_names(&self) -> bool {
+        self.needs_names
+    }
+
+    /// Executables are only hashed if some rules pin them
+    pub fn needs_hash(&self, exe: &str) -> bool {
+        self.pinned.iter().any(|&id| self.match_exe(id, exe))
+    }
+
+    /// The pinned hash if the rule has one and the executable doesn't match it
+    pub fn hash_mismatch(&self, rule_id: Option<usize>, packet: &PacketInfo) -> Option<&str> {
+        let id = rule_id?;
+        let expected = self.raw[id].sha256.as_ref()?;
+        // descendants are not checked
+        if !self.match_exe(id, packet.exe) {
+            return None;
         }
-        for ((ip, masklen), index) in v6_hashmap {
-            r.v6_table.insert(ip, masklen.into(), index);
+        match packet.exe_hash {
+            Some(hash) if hash.eq_ignore_ascii_case(expected) => None,
+            _ => Some(expected),
         }
+    }
 
-        r
+    /// Ancestors are only looked up if some rules match descendants
+    pub fn needs_ancestors(&self) -> bool {
+        self.needs_ancestors
+    }
+
+    /// The ancestor the rule matched instead of the process itself
+    pub fn matched_ancestor<'a>(
+        &self,
+        rule_id: Option<usize>,
+        packet: &PacketInfo<'a>,
+    ) -> Option<&'a Ancestor> {
+        let id = rule_id?;
+        if self.match_exe(id, packet.exe) {
+            return None;
+        }
+        self.raw[id].descendant_of(packet.ancestors, |exe| self.match_exe(id, exe))
+    }
+
+    fn match_exe(&self, id: usize, exe: &str) -> bool {
+        match_exe(&self.raw[id], self.exe_patterns.get(&id), exe)
+    }
+
+    /// What `Rule::script` is matched against, the arguments of interpreters only
+    pub fn script_args<'a>(&self, exe: &str, cmdline: &'a [String]) -> &'a [String] {
+        match cmdline.get(1..) {
+            Some(args) if is_interpreter(&self.interpreters, exe) => args,
+            _ => &[],
+        }
+    }
+
+    /// How long a `RuleTarget::Ask` connection is held
+    pub fn ask_timeout(&self) -> Duration {
+        self.ask_timeout
+    }
+
+    pub fn owner_policy(&self) -> OwnerPolicy {
+        self.owner_policy
+    }
+
+    /// The index of the packet with the strictest target, the first one of them if equal,
+    /// for `OwnerPolicy::All`
+    pub fn strictest(&self, packets: &[PacketInfo]) -> usize {
+        let strictness = |target: RuleTarget| match target {
+            RuleTarget::Accept => 0,
+            RuleTarget::RateLimit(_) => 1,
+            RuleTarget::Ask => 2,
+            RuleTarget::Drop | RuleTarget::Reject => 3,
+        };
+        packets
+            .iter()
+            .enumerate()
+            // `max_by_key` would return the last one
+            .min_by_key(|(_, packet)| Reverse(strictness(self.match_target(packet).1)))
+            .map_or(0, |(i, _)| i)
     }
 
     pub fn is_acceptable(
         &self,
-        device: Device,
-        protocol: Proto,
-        addr: SocketAddr,
+        packet: &PacketInfo,
         len: usize,
-        exe: &str,
-    ) -> (Option<usize>, bool) {
-        let mut hasher = DefaultHasher::new();
-        (device, protocol, addr, exe).hash(&mut hasher);
-        let lru_index = hasher.finish();
-
-        let mut cache = self.cache.borrow_mut();
-        let (rule_id, target) = cache.get(&lru_index).cloned().unwrap_or_else(|| {
-            let result = self.match_target(device, protocol, addr, exe);
-            cache.insert(lru_index, result);
+    ) -> (Option<usize>, RuleTarget, bool) {
+        let lru_index = self.cache_key(packet);
+        let shard = &self.cache[lru_index as usize % CACHE_SHARDS];
+        let cached = shard.lock().unwrap().get(&lru_index).cloned();
+        let (rule_id, target) = cached.unwrap_or_else(|| {
+            let result = self.match_target(packet);
+            shard.lock().unwrap().insert(lru_index, result);
             result
         });
 
-        let accept = match target {
-            RuleTarget::Accept => true,
-            RuleTarget::Drop => false,
-            RuleTarget::RateLimit(rate_id) => self.rate_state.borrow_mut()[rate_id].stuff(len),
-        };
-        (rule_id, accept)
+        (rule_id, target, self.apply_target(target, len))
     }
 
-    fn match_target(
+    /// Hashes the packet without the ports and local address no rule matches on, ephemeral
+    /// ports would make almost every lookup miss
+    fn cache_key(&self, packet: &PacketInfo) -> u64 {
+        let mut key = *packet;
+        if !self.needs_port {
+            key.addr.set_port(0);
+        }
+        if !self.needs_local_ip {
+            key.local_addr.set_ip(Ipv4Addr::UNSPECIFIED.into());
+        }
+        if !self.needs_local_port {
+            key.local_addr.set_port(0);
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// For packets whose owning process can't be found,
+    /// the returned rule id is an index to the unknown rules
+    pub fn is_acceptable_unknown(
         &self,
-        device: Device,
-        protocol: Proto,
-        addr: SocketAddr,
-        exe: &str,
-    ) -> (Option<usize>, RuleTarget) {
+        packet: &PacketInfo,
+        len: usize,
+    ) -> (Option<usize>, RuleTarget, bool) {
+        let (rule_id, target) = match self.unknown_policy {
+            UnknownPolicy::Accept => (None, RuleTarget::Accept),
+            UnknownPolicy::Drop => (None, RuleTarget::Drop),
+            // usually a short list, not worth indexing
+            UnknownPolicy::Chain => self
+                .unknown_rules
+                .iter()
+                .enumerate()
+                .find_map(|(id, rule)| {
+                    let pattern = self.unknown_patterns.get(&id);
+                    rule.match_target_with(packet, |exe| match_exe(rule, pattern, exe))
+                        .map(|t| (Some(id), t))
+                })
+                .unwrap_or((None, self.default_target)),
+        };
+        (rule_id, target, self.apply_target(target, len))
+    }
+
+    fn apply_target(&self, target: RuleTarget, len: usize) -> bool {
+        match target {
+            RuleTarget::Accept => true,
+            RuleTarget::Drop | RuleTarget::Reject => false,
+            // the verdict when there is no answer
+            RuleTarget::Ask => match self.ask_fallback {
+                RuleTarget::Ask => false,
+                fallback => self.apply_target(fallback, len),
+            },
+            RuleTarget::RateLimit(rate_id) => self.rate_state[rate_id].lock().unwrap().stuff(len),
+        }
+    }
+
+    pub fn match_target(&self, packet: &PacketInfo) -> (Option<usize>, RuleTarget) {
         let empty = Vec::new();
-        let exact_device = self.device.get(&device).unwrap_or(&empty);
-        let exact_proto = self.proto.get(&protocol).unwrap_or(&empty);
-        let exact_exe = self.exe.get(exe).unwrap_or(&empty);
+        let exact_device = self.device.get(&packet.device).unwrap_or(&empty);
+        let exact_proto = self.proto.get(&packet.protocol).unwrap_or(&empty);
+        let exact_interface = packet
+            .interface
+            .and_then(|name| self.interface.get(name))
+            .unwrap_or(&empty);
+        let mut exact_exe = self.exe.get(packet.exe).unwrap_or(&empty);
+        let inherited;
+        if !self.inherited.is_empty() && !packet.ancestors.is_empty() {
+            let mut candidates: Vec<usize> = packet
+                .ancestors
+                .iter()
+                .filter_map(|ancestor| self.inherited.get(&ancestor.exe))
+                .flatten()
+                .chain(exact_exe)
+                .cloned()
+                .collect();
+            candidates.sort_unstable();
+            candidates.dedup();
+            inherited = candidates;
+            exact_exe = &inherited;
+        }
+        let exact_uid = packet
+            .uid
+            .and_then(|uid| self.uid.get(&uid))
+            .unwrap_or(&empty);
+        let exact_gid = packet
+            .gid
+            .and_then(|gid| self.gid.get(&gid))
+            .unwrap_or(&empty);
+        let exact_cgroup = &self.cgroup_candidates(packet.cgroup);
+        let exact_app_id = packet
+            .app_id
+            .and_then(|id| self.app_id.get(id))
+            .unwrap_or(&empty);
         let exact_port = &self
             .port
-            .query_point(addr.port())
+            .query_point(packet.addr.port())
             .map(|v| v.value)
             .collect::<Vec<_>>(); // TODO: zero alloc
-        let (exact_ip, any_ip) = match addr.ip() {
-            IpAddr::V4(ip) => (
-                self.v4_table
-                    .longest_match(ip)
-                    .map(|x| x.2)
-                    .unwrap_or(&empty),
-                &self.any_v4,
-            ),
-            IpAddr::V6(ip) => (
-                self.v6_table
-                    .longest_match(ip)
-                    .map(|x| x.2)
-                    .unwrap_or(&empty),
-                &self.any_v6,
-            ),
-        };
+        let exact_local_port = &self
+            .local_port
+            .query_point(packet.local_addr.port())
+            .map(|v| v.value)
+            .collect::<Vec<_>>();
+        let (exact_ip, any_ip) = self.subnet.candidates(packet.addr.ip());
+        let (exact_local_ip, any_local_ip) = self.local_subnet.candidates(packet.local_addr.ip());
         let list = [
             (exact_device, &self.any_device),
             (exact_proto, &self.any_proto),
+            (exact_interface, &self.any_interface),
             (exact_exe, &self.any_exe),
+            (exact_uid, &self.any_uid),
+            (exact_gid, &self.any_gid),
+            (exact_cgroup, &self.any_cgroup),
+            (exact_app_id, &self.any_app_id),
             (exact_port, &self.any_port),
-            (exact_ip, any_ip),
+            (exact_local_port, &self.any_local_port),
+            (exact_ip.unwrap_or(&empty), any_ip),
+            (exact_local_ip.unwrap_or(&empty), any_local_ip),
         ];
         let (exact, any) = list
             .iter()
@@ -223,30 +588,120 @@ impl IndexedRules {
             .chain(*any)
             .filter_map(|&id| {
                 self.raw[id]
-                    .match_target(device, protocol, addr, exe)
+                    .match_target_with(packet, |exe| self.match_exe(id, exe))
                     .map(|t| (id, t))
             })
             .min_by_key(|(id, _)| *id)
-            .map(|(id, t)| (Some(id), t))
+            .map(|(id, t)| {
+                if self.hash_mismatch(Some(id), packet).is_some() {
+                    (Some(id), self.hash_mismatch)
+                } else {
+                    (Some(id), t)
+                }
+            })
             .unwrap_or((None, self.default_target))
     }
+
+    /// Rules keyed by the cgroup, its parents, or the units in its path
+    fn cgroup_candidates(&self, cgroup: &str) -> Vec<usize> {
+        if cgroup.is_empty() || self.cgroup.is_empty() {
+            return Vec::new();
+        }
+        let parents = cgroup
+            .match_indices('/')
+            .map(|(i, _)| &cgroup[..i])
+            .chain(Some(cgroup));
+        let units = cgroup.split('/').filter(|unit| !unit.is_empty());
+        let mut candidates: Vec<usize> = parents
+            .chain(units)
+            .filter_map(|key| self.cgroup.get(key))
+            .flatten()
+            .cloned()
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
 }
 
 impl From<Rules> for IndexedRules {
     fn from(r: Rules) -> Self {
-        Self::new(
+        let mut indexed = Self::with_unknown(
             r.default_target,
             r.rules,
             r.rate_rules.into_iter().map(|r| r.limit).collect(),
-        )
+            r.unknown_policy,
+            r.unknown_rules,
+        );
+        indexed.ask_timeout = Duration::from_secs(r.ask_timeout.into());
+        indexed.ask_fallback = r.ask_fallback;
+        indexed.interpreters = r.interpreters;
+        indexed.hash_mismatch = r.hash_mismatch;
+        indexed.owner_policy = r.owner_policy;
+        indexed
     }
 }
 
+/// `Rule::match_exe` with its pattern compiled, an invalid pattern matches nothing
+fn match_exe(rule: &Rule, pattern: Option<&Regex>, exe: &str) -> bool {
+    match rule.exe_match {
+        ExeMatch::Exact => rule.match_exe(exe),
+        _ => pattern.map_or(false, |pattern| pattern.is_match(exe)),
+    }
+}
+
+/// Rules from clients are checked before they are applied, `IndexedRules` would only warn
+pub fn validate(rules: &Rules) -> Result<(), String> {
+    let lists = [
+        ("Rule", &rules.rules),
+        ("Unknown process rule", &rules.unknown_rules),
+    ];
+    for (name, list) in lists.iter() {
+        for (index, rule) in list.iter().enumerate() {
+            if let Some(Err(e)) = rule.exe_regex() {
+                return Err(format!(
+                    "{} {}: invalid exe pattern: {}",
+                    name,
+                    index + 1,
+                    e
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use gleipnir_interface::script_arg;
+    use std::net::SocketAddr;
     use std::ops::RangeInclusive;
 
+    fn packet(device: Device, protocol: Proto, addr: SocketAddr) -> PacketInfo<'static> {
+        PacketInfo {
+            device,
+            protocol,
+            addr,
+            local_addr: (Ipv4Addr::UNSPECIFIED, 0).into(),
+            icmp: None,
+            interface: None,
+            exe: "",
+            uid: None,
+            gid: None,
+            cgroup: "",
+            app_id: None,
+            args: &[],
+            ancestors: &[],
+            exe_hash: None,
+            names: &[],
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|&arg| arg.to_owned()).collect()
+    }
+
     #[test]
     fn rules_indexing() {
         let raw_rules = vec![
@@ -257,6 +712,7 @@ mod test {
                 port: None,
                 subnet: Some(([1, 1, 1, 1].into(), 32)),
                 target: RuleTarget::Accept,
+                ..Default::default()
             },
             Rule {
                 device: Some(Device::Input),
@@ -265,6 +721,7 @@ mod test {
                 port: None,
                 subnet: Some(([1, 1, 1, 1].into(), 32)),
                 target: RuleTarget::Accept,
+                ..Default::default()
             },
             Rule {
                 device: Some(Device::Input),
@@ -273,6 +730,7 @@ mod test {
                 port: None,
                 subnet: Some(([2, 2, 2, 2].into(), 30)),
                 target: RuleTarget::Accept,
+                ..Default::default()
             },
             Rule {
                 device: Some(Device::Input),
@@ -281,6 +739,7 @@ mod test {
                 port: Some(RangeInclusive::new(10, 200)),
                 subnet: Some(([2, 2, 2, 2].into(), 32)),
                 target: RuleTarget::Accept,
+                ..Default::default()
             },
             Rule {
                 device: Some(Device::Input),
@@ -289,6 +748,7 @@ mod test {
                 port: Some(RangeInclusive::new(100, 100)),
                 subnet: Some(([0, 0, 0, 0].into(), 0)),
                 target: RuleTarget::Accept,
+                ..Default::default()
             },
         ];
 
@@ -329,8 +789,809 @@ mod test {
         assert_eq!(r.default_target, RuleTarget::Drop);
 
         assert_eq!(
-            r.is_acceptable(Device::Input, Proto::Tcp, ([2, 2, 2, 2], 100).into(), 0, "",),
-            (Some(3), true)
+            r.is_acceptable(
+                &packet(Device::Input, Proto::Tcp, ([2, 2, 2, 2], 100).into()),
+                0
+            ),
+            (Some(3), RuleTarget::Accept, true)
+        );
+    }
+
+    #[test]
+    fn unknown_process() {
+        let unknown_rules = vec![Rule {
+            device: Some(Device::Output),
+            proto: Some(Proto::Udp),
+            exe: None,
+            port: Some(RangeInclusive::new(53, 53)),
+            subnet: None,
+            target: RuleTarget::Accept,
+            ..Default::default()
+        }];
+        let addr = ([1, 1, 1, 1], 53).into();
+
+        let r = IndexedRules::new(RuleTarget::Drop, vec![], vec![]);
+        assert_eq!(
+            r.is_acceptable_unknown(&packet(Device::Output, Proto::Udp, addr), 0),
+            (None, RuleTarget::Accept, true)
+        );
+
+        let r = IndexedRules::with_unknown(
+            RuleTarget::Accept,
+            vec![],
+            vec![],
+            UnknownPolicy::Drop,
+            unknown_rules.clone(),
+        );
+        assert_eq!(
+            r.is_acceptable_unknown(&packet(Device::Output, Proto::Udp, addr), 0),
+            (None, RuleTarget::Drop, false)
+        );
+
+        let r = IndexedRules::with_unknown(
+            RuleTarget::Drop,
+            vec![],
+            vec![],
+            UnknownPolicy::Chain,
+            unknown_rules,
+        );
+        assert_eq!(
+            r.is_acceptable_unknown(&packet(Device::Output, Proto::Udp, addr), 0),
+            (Some(0), RuleTarget::Accept, true)
+        );
+        assert_eq!(
+            r.is_acceptable_unknown(&packet(Device::Output, Proto::Tcp, addr), 0),
+            (None, RuleTarget::Drop, false)
+        );
+    }
+
+    #[test]
+    fn icmp() {
+        let raw_rules = vec![
+            Rule {
+                proto: Some(Proto::Icmp),
+                icmp_type: Some(8),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                icmp_type: Some(3),
+                icmp_code: Some(4),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Accept, raw_rules, vec![]);
+        let icmp = |icmp_type, icmp_code| PacketInfo {
+            icmp: Some((icmp_type, icmp_code)),
+            ..packet(Device::Output, Proto::Icmp, ([1, 1, 1, 1], 0).into())
+        };
+
+        assert_eq!(r.match_target(&icmp(8, 0)), (Some(0), RuleTarget::Drop));
+        assert_eq!(r.match_target(&icmp(0, 0)), (None, RuleTarget::Accept));
+        assert_eq!(r.match_target(&icmp(3, 4)), (Some(1), RuleTarget::Drop));
+        assert_eq!(r.match_target(&icmp(3, 1)), (None, RuleTarget::Accept));
+        // ICMP rules never match other protocols
+        assert_eq!(
+            r.match_target(&packet(
+                Device::Output,
+                Proto::Tcp,
+                ([1, 1, 1, 1], 0).into()
+            )),
+            (None, RuleTarget::Accept)
+        );
+    }
+
+    #[test]
+    fn ask_fallback() {
+        let addr = ([1, 1, 1, 1], 443).into();
+        let rules = Rules {
+            default_target: RuleTarget::Ask,
+            ask_timeout: 5,
+            ask_fallback: RuleTarget::Accept,
+            ..Default::default()
+        };
+        let r = IndexedRules::from(rules);
+        assert_eq!(r.ask_timeout(), Duration::from_secs(5));
+        assert_eq!(
+            r.is_acceptable(&packet(Device::Output, Proto::Tcp, addr), 0),
+            (None, RuleTarget::Ask, true)
+        );
+
+        let r = IndexedRules::new(RuleTarget::Ask, vec![], vec![]);
+        assert_eq!(
+            r.is_acceptable(&packet(Device::Output, Proto::Tcp, addr), 0),
+            (None, RuleTarget::Ask, false)
+        );
+    }
+
+    #[test]
+    fn domain() {
+        let raw_rules = vec![
+            Rule {
+                domain: Some("*.example.com".to_owned()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                subnet: Some(([1, 1, 1, 1].into(), 32)),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Accept, raw_rules, vec![]);
+        assert!(r.needs_names());
+        let names = vec!["example.com".to_owned(), "cdn.EXAMPLE.com".to_owned()];
+        let with_names = |addr, names| PacketInfo {
+            names,
+            ..packet(Device::Output, Proto::Tcp, addr)
+        };
+
+        assert_eq!(
+            r.match_target(&with_names(([1, 1, 1, 1], 443).into(), &names)),
+            (Some(0), RuleTarget::Drop)
+        );
+        assert_eq!(
+            r.match_target(&with_names(([1, 1, 1, 1], 443).into(), &names[..1])),
+            (Some(1), RuleTarget::Accept)
+        );
+        assert_eq!(
+            r.match_target(&with_names(([2, 2, 2, 2], 443).into(), &[])),
+            (None, RuleTarget::Accept)
+        );
+        assert!(!IndexedRules::new(RuleTarget::Accept, vec![], vec![]).needs_names());
+    }
+
+    #[test]
+    fn owner() {
+        let raw_rules = vec![
+            Rule {
+                uid: Some(1000),
+                gid: Some(100),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                uid: Some(1000),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                gid: Some(0),
+                target: RuleTarget::Reject,
+                ..Default::default()
+            },
+        ];
+        let r = IndexedRules::new(RuleTarget::Accept, raw_rules, vec![]);
+        let addr = ([1, 1, 1, 1], 443).into();
+        let owned_by = |uid, gid| PacketInfo {
+            uid,
+            gid,
+            ..packet(Device::Output, Proto::Tcp, addr)
+        };
+
+        assert_eq!(
+            r.match_target(&owned_by(Some(1000), Some(100))),
+            (Some(0), RuleTarget::Accept)
+        );
+        assert_eq!(
+            r.match_target(&owned_by(Some(1000), Some(1000))),
+            (Some(1), RuleTarget::Drop)
+        );
+        assert_eq!(
+            r.match_target(&owned_by(Some(0), Some(0))),
+            (Some(2), RuleTarget::Reject)
+        );
+        // owned by the kernel
+        assert_eq!(
+            r.match_target(&owned_by(None, None)),
+            (None, RuleTarget::Accept)
+        );
+        assert_eq!(r.uid[&1000], vec![0, 1]);
+        assert_eq!(r.any_uid, vec![2]);
+    }
+
+    #[test]
+    fn cgroup() {
+        let raw_rules = vec![
+            Rule {
+                cgroup: Some("apt-daily.service".to_owned()),
+                subnet: Some(([1, 1, 1, 1].into(), 32)),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                cgroup: Some("apt-daily.service".to_owned()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                cgroup: Some("app-firefox-*.scope".to_owned()),
+                target: RuleTarget::Reject,
+                ..Default::default()
+            },
+            Rule {
+                cgroup: Some("/user.slice/".to_owned()),
+                target: RuleTarget::Ask,
+                ..Default::default()
+            },
+        ];
+
+        let r = IndexedRules::new(RuleTarget::Accept, raw_rules, vec![]);
+        assert_eq!(r.cgroup["apt-daily.service"], vec![0, 1]);
+        assert_eq!(r.cgroup["/user.slice"], vec![3]);
+        assert_eq!(r.any_cgroup, vec![2]);
+
+        let apt = "/system.slice/apt-daily.service";
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                cgroup: apt,
+                ..packet(Device::Output, Proto::Tcp, ([1, 1, 1, 1], 443).into())
+            }),
+            (Some(0), RuleTarget::Accept)
+        );
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                cgroup: apt,
+                ..packet(Device::Output, Proto::Tcp, ([2, 2, 2, 2], 80).into())
+            }),
+            (Some(1), RuleTarget::Drop)
+        );
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                cgroup:
+                    "/user.slice/user-1000.slice/user@1000.service/app.slice/app-firefox-42.scope",
+                ..packet(Device::Output, Proto::Tcp, ([2, 2, 2, 2], 80).into())
+            }),
+            (Some(2), RuleTarget::Reject)
+        );
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                cgroup: "/user.slice/user-1000.slice/session-2.scope",
+                ..packet(Device::Output, Proto::Tcp, ([2, 2, 2, 2], 80).into())
+            }),
+            (Some(3), RuleTarget::Ask)
+        );
+        // not a parent, only a common prefix
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                cgroup: "/user.slice2",
+                ..packet(Device::Output, Proto::Tcp, ([2, 2, 2, 2], 80).into())
+            }),
+            (None, RuleTarget::Accept)
+        );
+    }
+
+    #[test]
+    fn app_id() {
+        let raw_rules = vec![
+            Rule {
+                app_id: Some("flatpak:org.mozilla.firefox".to_owned()),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                app_id: Some("docker:*".to_owned()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+        ];
+
+        let r = IndexedRules::new(RuleTarget::Reject, raw_rules, vec![]);
+        assert_eq!(r.app_id["flatpak:org.mozilla.firefox"], vec![0]);
+        assert_eq!(r.any_app_id, vec![1]);
+
+        let addr = ([1, 1, 1, 1], 443).into();
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                exe: "/usr/bin/bwrap",
+                app_id: Some("flatpak:org.mozilla.firefox"),
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (Some(0), RuleTarget::Accept)
+        );
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                exe: "/usr/bin/bwrap",
+                app_id: Some("flatpak:org.gnome.Maps"),
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (None, RuleTarget::Reject)
+        );
+        let container = format!("docker:{}", "0f".repeat(32));
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                app_id: Some(container.as_str()),
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (Some(1), RuleTarget::Drop)
+        );
+        // not sandboxed
+        assert_eq!(
+            r.match_target(&packet(Device::Output, Proto::Tcp, addr)),
+            (None, RuleTarget::Reject)
+        );
+    }
+
+    #[test]
+    fn interface() {
+        let raw_rules = vec![
+            Rule {
+                interface: Some("wlan0".to_owned()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                interface: Some("wg*".to_owned()),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+        ];
+
+        let r = IndexedRules::new(RuleTarget::Reject, raw_rules, vec![]);
+        assert_eq!(r.interface["wlan0"], vec![0]);
+        assert_eq!(r.any_interface, vec![1]);
+
+        let addr = ([10, 0, 0, 1], 51820).into();
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                interface: Some("wlan0"),
+                ..packet(Device::Output, Proto::Udp, addr)
+            }),
+            (Some(0), RuleTarget::Drop)
+        );
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                interface: Some("wg-home"),
+                ..packet(Device::Output, Proto::Udp, addr)
+            }),
+            (Some(1), RuleTarget::Accept)
+        );
+        // the interface is gone
+        assert_eq!(
+            r.match_target(&packet(Device::Output, Proto::Udp, addr)),
+            (None, RuleTarget::Reject)
+        );
+    }
+
+    #[test]
+    fn local_address() {
+        let raw_rules = vec![
+            Rule {
+                device: Some(Device::Input),
+                local_port: Some(RangeInclusive::new(22, 22)),
+                subnet: Some(([192, 168, 1, 0].into(), 24)),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                device: Some(Device::Input),
+                local_port: Some(RangeInclusive::new(22, 22)),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                local_subnet: Some(([127, 0, 0, 0].into(), 8)),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                local_subnet: Some(([10, 0, 0, 0].into(), 8)),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                local_subnet: Some(([10, 1, 0, 0].into(), 16)),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+        ];
+
+        let r = IndexedRules::new(RuleTarget::Reject, raw_rules, vec![]);
+        let mut local_port: Vec<_> = r.local_port.query_point(22).map(|e| e.value).collect();
+        local_port.sort_unstable();
+        assert_eq!(local_port, vec![0, 1]);
+        assert_eq!(r.any_local_port, vec![2, 3, 4]);
+        assert_eq!(r.local_subnet.any_v4, vec![0, 1]);
+
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                local_addr: ([192, 168, 1, 2], 22).into(),
+                ..packet(Device::Input, Proto::Tcp, ([192, 168, 1, 7], 50000).into())
+            }),
+            (Some(0), RuleTarget::Accept)
+        );
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                local_addr: ([192, 168, 1, 2], 22).into(),
+                ..packet(Device::Input, Proto::Tcp, ([1, 1, 1, 1], 50000).into())
+            }),
+            (Some(1), RuleTarget::Drop)
+        );
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                local_addr: ([127, 0, 0, 1], 8080).into(),
+                ..packet(Device::Input, Proto::Tcp, ([127, 0, 0, 1], 50000).into())
+            }),
+            (Some(2), RuleTarget::Accept)
+        );
+        // the first rule wins, not the longest prefix
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                local_addr: ([10, 1, 2, 3], 8080).into(),
+                ..packet(Device::Input, Proto::Tcp, ([1, 1, 1, 1], 50000).into())
+            }),
+            (Some(3), RuleTarget::Drop)
+        );
+    }
+
+    #[test]
+    fn cache_key() {
+        let raw_rules = vec![Rule {
+            port: Some(RangeInclusive::new(443, 443)),
+            target: RuleTarget::Accept,
+            ..Default::default()
+        }];
+        let flow = |local_port| PacketInfo {
+            local_addr: ([192, 168, 1, 2], local_port).into(),
+            ..packet(Device::Output, Proto::Tcp, ([1, 1, 1, 1], 443).into())
+        };
+
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules.clone(), vec![]);
+        assert_eq!(r.cache_key(&flow(50000)), r.cache_key(&flow(50001)));
+        assert_ne!(
+            r.cache_key(&flow(50000)),
+            r.cache_key(&PacketInfo {
+                addr: ([1, 1, 1, 1], 80).into(),
+                ..flow(50000)
+            })
+        );
+        assert_eq!(
+            r.is_acceptable(&flow(50000), 0),
+            (Some(0), RuleTarget::Accept, true)
+        );
+        assert_eq!(
+            r.is_acceptable(&flow(50001), 0),
+            (Some(0), RuleTarget::Accept, true)
+        );
+
+        let mut raw_rules = raw_rules;
+        raw_rules.insert(
+            0,
+            Rule {
+                local_port: Some(RangeInclusive::new(50001, 50001)),
+                target: RuleTarget::Reject,
+                ..Default::default()
+            },
+        );
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules, vec![]);
+        assert_ne!(r.cache_key(&flow(50000)), r.cache_key(&flow(50001)));
+        assert_eq!(
+            r.is_acceptable(&flow(50000), 0),
+            (Some(1), RuleTarget::Accept, true)
+        );
+        assert_eq!(
+            r.is_acceptable(&flow(50001), 0),
+            (Some(0), RuleTarget::Reject, false)
+        );
+    }
+
+    #[test]
+    fn script() {
+        let raw_rules = vec![Rule {
+            exe: Some("/usr/bin/python3.8".to_owned()),
+            script: Some("*/backup.py".to_owned()),
+            target: RuleTarget::Accept,
+            ..Default::default()
+        }];
+
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules, vec![]);
+        let cmdline = args(&["python3", "-u", "/home/user/backup.py", "--all"]);
+        assert_eq!(r.script_args("/usr/bin/python3.8", &cmdline), &cmdline[1..]);
+        // not an interpreter, the arguments are not looked at
+        assert!(r.script_args("/usr/bin/backup", &cmdline).is_empty());
+
+        let addr = ([1, 1, 1, 1], 443).into();
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                exe: "/usr/bin/python3.8",
+                args: &cmdline[1..],
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (Some(0), RuleTarget::Accept)
+        );
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                exe: "/usr/bin/python3.8",
+                args: &args(&["-W", "ignore", "/home/user/backup.py"]),
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (Some(0), RuleTarget::Accept)
+        );
+        // only the script itself, not its arguments or code run with -c
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                exe: "/usr/bin/python3.8",
+                args: &args(&["/tmp/evil.py", "/home/user/backup.py"]),
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (None, RuleTarget::Drop)
+        );
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                exe: "/usr/bin/python3.8",
+                args: &args(&["-c", "import os", "/home/user/backup.py"]),
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (None, RuleTarget::Drop)
+        );
+
+        assert_eq!(
+            script_arg("/usr/bin/python3", &args(&["-mhttp.server"])),
+            Some("http.server")
+        );
+        assert_eq!(
+            script_arg("/usr/bin/java", &args(&["-Xmx1g", "-jar", "app.jar", "x"])),
+            Some("app.jar")
+        );
+        assert_eq!(
+            script_arg(
+                "/usr/bin/node",
+                &args(&["--require", "./hook.js", "app.js"])
+            ),
+            Some("app.js")
+        );
+        assert_eq!(
+            script_arg("/usr/bin/bash", &args(&["-ec", "curl x", "run.sh"])),
+            None
+        );
+    }
+
+    #[test]
+    fn descendants() {
+        let raw_rules = vec![
+            Rule {
+                exe: Some("/usr/lib/firefox/firefox".to_owned()),
+                descendants: true,
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/usr/bin/bash".to_owned()),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+        ];
+
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules, vec![]);
+        assert!(r.needs_ancestors());
+
+        let ancestors = vec![
+            Ancestor {
+                pid: 1001,
+                exe: "/usr/bin/bash".to_owned(),
+            },
+            Ancestor {
+                pid: 1000,
+                exe: "/usr/lib/firefox/firefox".to_owned(),
+            },
+        ];
+        let addr = ([1, 1, 1, 1], 443).into();
+        let curl = PacketInfo {
+            exe: "/usr/bin/curl",
+            ancestors: &ancestors,
+            ..packet(Device::Output, Proto::Tcp, addr)
+        };
+        assert_eq!(r.match_target(&curl), (Some(0), RuleTarget::Accept));
+        assert_eq!(r.matched_ancestor(Some(0), &curl), Some(&ancestors[1]));
+        // only the rules for descendants are inherited
+        let curl = PacketInfo {
+            ancestors: &ancestors[..1],
+            ..curl
+        };
+        assert_eq!(r.match_target(&curl), (None, RuleTarget::Drop));
+        let firefox = PacketInfo {
+            exe: "/usr/lib/firefox/firefox",
+            ..packet(Device::Output, Proto::Tcp, addr)
+        };
+        assert_eq!(r.match_target(&firefox), (Some(0), RuleTarget::Accept));
+        assert_eq!(r.matched_ancestor(Some(0), &firefox), None);
+    }
+
+    #[test]
+    fn exe_patterns() {
+        let raw_rules = vec![
+            Rule {
+                exe: Some("/usr/bin/app".to_owned()),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/opt/app-*/bin/app".to_owned()),
+                exe_match: ExeMatch::Glob,
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+            Rule {
+                exe: Some(r"/nix/store/[0-9a-z]{32}-foo-[^/]+/bin/foo".to_owned()),
+                exe_match: ExeMatch::Regex,
+                target: RuleTarget::Reject,
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("(".to_owned()),
+                exe_match: ExeMatch::Regex,
+                target: RuleTarget::Accept,
+                ..Default::default()
+            },
+        ];
+
+        let r = IndexedRules::new(RuleTarget::Drop, raw_rules.clone(), vec![]);
+        assert_eq!(r.exe["/usr/bin/app"], vec![0]);
+        assert_eq!(r.any_exe, vec![1, 2, 3]);
+        assert_eq!(r.exe_patterns.len(), 2);
+
+        let addr = ([1, 1, 1, 1], 443).into();
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                exe: "/opt/app-1.2.3/bin/app",
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (Some(1), RuleTarget::Accept)
+        );
+        // the whole path must match
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                exe: "/opt/app-1.2.3/bin/app-helper",
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (None, RuleTarget::Drop)
+        );
+        let foo = format!("/nix/store/{}-foo-1.0/bin/foo", "a".repeat(32));
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                exe: &foo,
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (Some(2), RuleTarget::Reject)
+        );
+        // an invalid pattern matches nothing
+        assert_eq!(
+            r.match_target(&PacketInfo {
+                exe: "(",
+                ..packet(Device::Output, Proto::Tcp, addr)
+            }),
+            (None, RuleTarget::Drop)
+        );
+
+        let rules = Rules {
+            rules: raw_rules[..3].to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(validate(&rules), Ok(()));
+        let rules = Rules {
+            unknown_rules: raw_rules[3..].to_vec(),
+            ..rules
+        };
+        assert!(validate(&rules)
+            .unwrap_err()
+            .starts_with("Unknown process rule 1: invalid exe pattern"));
+    }
+
+    #[test]
+    fn sha256() {
+        let pin = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        let rules = Rules {
+            default_target: RuleTarget::Drop,
+            rules: vec![Rule {
+                exe: Some("/usr/bin/app".to_owned()),
+                descendants: true,
+                sha256: Some(pin.to_owned()),
+                target: RuleTarget::Accept,
+                ..Default::default()
+            }],
+            hash_mismatch: RuleTarget::Reject,
+            ..Default::default()
+        };
+
+        let r = IndexedRules::from(rules);
+        assert!(r.needs_hash("/usr/bin/app"));
+        assert!(!r.needs_hash("/usr/bin/other"));
+
+        let addr = ([1, 1, 1, 1], 443).into();
+        let upper = pin.to_uppercase();
+        let app = PacketInfo {
+            exe: "/usr/bin/app",
+            exe_hash: Some(upper.as_str()),
+            ..packet(Device::Output, Proto::Tcp, addr)
+        };
+        assert_eq!(r.match_target(&app), (Some(0), RuleTarget::Accept));
+        assert_eq!(r.hash_mismatch(Some(0), &app), None);
+        let app = PacketInfo {
+            exe_hash: Some("00"),
+            ..app
+        };
+        assert_eq!(r.match_target(&app), (Some(0), RuleTarget::Reject));
+        assert_eq!(r.hash_mismatch(Some(0), &app), Some(pin));
+        // unreadable
+        let app = PacketInfo {
+            exe_hash: None,
+            ..app
+        };
+        assert_eq!(r.match_target(&app), (Some(0), RuleTarget::Reject));
+
+        // descendants are not checked
+        let ancestors = [Ancestor {
+            pid: 1000,
+            exe: "/usr/bin/app".to_owned(),
+        }];
+        let helper = PacketInfo {
+            exe: "/usr/bin/helper",
+            exe_hash: Some("00"),
+            ancestors: &ancestors,
+            ..packet(Device::Output, Proto::Tcp, addr)
+        };
+        assert_eq!(r.match_target(&helper), (Some(0), RuleTarget::Accept));
+        assert_eq!(r.hash_mismatch(Some(0), &helper), None);
+    }
+
+    #[test]
+    fn strictest() {
+        let raw_rules = vec![
+            Rule {
+                exe: Some("/usr/bin/limited".to_owned()),
+                target: RuleTarget::RateLimit(0),
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/usr/bin/asked".to_owned()),
+                target: RuleTarget::Ask,
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/usr/bin/dropped".to_owned()),
+                target: RuleTarget::Drop,
+                ..Default::default()
+            },
+            Rule {
+                exe: Some("/usr/bin/rejected".to_owned()),
+                target: RuleTarget::Reject,
+                ..Default::default()
+            },
+        ];
+
+        let r = IndexedRules::new(RuleTarget::Accept, raw_rules, vec![100]);
+        let addr = ([1, 1, 1, 1], 443).into();
+        let owners = |exes: &[&'static str]| -> Vec<_> {
+            exes.iter()
+                .map(|&exe| PacketInfo {
+                    exe,
+                    ..packet(Device::Output, Proto::Tcp, addr)
+                })
+                .collect()
+        };
+        assert_eq!(
+            r.strictest(&owners(&["/usr/bin/app", "/usr/bin/limited"])),
+            1
+        );
+        // asking is stricter than rate limiting
+        assert_eq!(
+            r.strictest(&owners(&[
+                "/usr/bin/asked",
+                "/usr/bin/limited",
+                "/usr/bin/app"
+            ])),
+            0
+        );
+        // the first one of equally strict owners
+        assert_eq!(
+            r.strictest(&owners(&[
+                "/usr/bin/asked",
+                "/usr/bin/dropped",
+                "/usr/bin/rejected"
+            ])),
+            1
         );
+        assert_eq!(r.strictest(&[]), 0);
     }
 }
@@ -0,0 +1,417 @@
+//! Finding the socket of a packet with `sock_diag`, see `config::SockDiagMode`
+//!
+//! In `Query` mode every new flow sends one request for each address it may be bound to.
+//! In `Dump` mode all sockets of the protocols looked up so far are kept in a table shared
+//! by the workers, dumped again every `DUMP_INTERVAL` by a thread of its own. Sockets closed
+//! in the meantime are removed when the kernel tells about it, TCP and UDP only. Sockets
+//! newer than the dump are queried when no candidate is in the table, and added to it.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gleipnir_interface::Proto;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+
+use crate::netlink::{InetDiagMsg, SockDestroyed, SockDiag};
+
+/// Also how long a socket closed without telling may be found
+const DUMP_INTERVAL: Duration = Duration::from_secs(10);
+/// Sockets of each protocol measured by `bench`
+const BENCH_SAMPLES: usize = 1000;
+/// Sockets created by `bench` after the dump
+const BENCH_FRESH: usize = 100;
+
+/// Local and remote address
+type Candidate = (SocketAddr, SocketAddr);
+type Sockets = HashMap<Candidate, Vec<InetDiagMsg>>;
+
+// `include/net/tcp_states.h`
+const TCP_SYN_RECV: u8 = 3;
+const TCP_FIN_WAIT1: u8 = 4;
+const TCP_FIN_WAIT2: u8 = 5;
+const TCP_TIME_WAIT: u8 = 6;
+const TCP_LAST_ACK: u8 = 9;
+const TCP_CLOSING: u8 = 11;
+const TCP_NEW_SYN_RECV: u8 = 12;
+
+/// Ports of services the kernel talks to, or listens on, by itself
+const KERNEL_SERVICES: &[(u16, &str)] = &[
+    (53, "dns"),
+    (111, "rpcbind"),
+    (445, "smb"),
+    (2049, "nfs"),
+    (3260, "iscsi"),
+    (4789, "vxlan"),
+    (6081, "geneve"),
+    (51820, "wireguard"),
+];
+
+/// A connection being accepted, its packets are sent on behalf of the listener
+pub fn is_request(diag_msg: &InetDiagMsg) -> bool {
+    diag_msg.idiag_inode == 0
+        && (diag_msg.idiag_state == TCP_NEW_SYN_RECV || diag_msg.idiag_state == TCP_SYN_RECV)
+}
+
+/// What a socket without an inode is used for, the services are guessed from their ports
+pub fn kernel_subsystem(protocol: Proto, diag_msg: &InetDiagMsg) -> Option<&'static str> {
+    if protocol == Proto::Tcp {
+        match diag_msg.idiag_state {
+            TCP_TIME_WAIT => return Some("timewait"),
+            // closed by its process, the kernel finishes the job
+            TCP_FIN_WAIT1 | TCP_FIN_WAIT2 | TCP_LAST_ACK | TCP_CLOSING => return Some("orphan"),
+            _ => (),
+        }
+    }
+    let ports = [
+        diag_msg.local_address().port(),
+        diag_msg.remote_address().port(),
+    ];
+    KERNEL_SERVICES
+        .iter()
+        .find(|(port, _)| ports.contains(port))
+        .map(|&(_, name)| name)
+}
+
+/// Where a listener, or an unconnected socket, of the local address may be bound
+pub fn listeners(local: SocketAddr) -> [Candidate; 2] {
+    let unspecified: IpAddr = if local.is_ipv4() {
+        Ipv4Addr::UNSPECIFIED.into()
+    } else {
+        Ipv6Addr::UNSPECIFIED.into()
+    };
+    let unspecified_socket = SocketAddr::new(unspecified, 0);
+    [
+        (local, unspecified_socket),
+        (
+            SocketAddr::new(unspecified, local.port()),
+            unspecified_socket,
+        ),
+    ]
+}
+
+/// The sockets a packet may belong to, the connected one first
+pub fn candidates(protocol: Proto, local: SocketAddr, remote: SocketAddr) -> Vec<Candidate> {
+    let mut candidates = vec![(local, remote)];
+    match protocol {
+        Proto::Tcp => (),
+        // ping sockets are like UDP sockets, the echo identifier is the local port
+        Proto::Udp | Proto::UdpLite | Proto::Icmp | Proto::Icmpv6 => {
+            candidates.extend_from_slice(&listeners(local))
+        }
+    }
+    candidates
+}
+
+/// One request for each candidate, in order, until one is found
+pub fn query(
+    diag: &mut SockDiag,
+    protocol: Proto,
+    candidates: &[Candidate],
+) -> Result<Vec<InetDiagMsg>, io::Error> {
+    for &(local_address, remote_address) in candidates {
+        match diag.query(protocol, local_address, remote_address) {
+            Ok(r) => return Ok(r),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::ErrorKind::NotFound.into())
+}
+
+/// Shared by all workers, see `SocketTable::watch`
+pub struct SocketTable {
+    /// Only the protocols looked up so far are dumped
+    protocols: RwLock<HashMap<Proto, Sockets>>,
+}
+
+impl SocketTable {
+    fn new() -> Self {
+        SocketTable {
+            protocols: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// The table is dumped and updated by a thread of its own, see `refresh`
+    pub fn watch() -> Result<Arc<Self>, io::Error> {
+        let diag = SockDiag::new()?;
+        let destroyed = match SockDestroyed::new() {
+            Ok(destroyed) => Some(destroyed),
+            Err(e) => {
+                eprintln!(
+                    "WARNING: no socket events, closed sockets are kept until the next dump: {}",
+                    e
+                );
+                None
+            }
+        };
+        let table = Arc::new(SocketTable::new());
+        let refreshed = table.clone();
+        thread::spawn(move || refreshed.refresh(diag, destroyed));
+        Ok(table)
+    }
+
+    /// The first candidate in the table, only if none of them is there the first one found
+    /// by `query`
+    ///
+    /// A connection accepted after the dump is found as its listener until the next one.
+    pub fn find(
+        &self,
+        diag: &mut SockDiag,
+        protocol: Proto,
+        candidates: &[Candidate],
+    ) -> Result<Vec<InetDiagMsg>, io::Error> {
+        if !self.protocols.read().unwrap().contains_key(&protocol) {
+            // dumped from now on
+            self.protocols.write().unwrap().entry(protocol).or_default();
+        }
+        {
+            let protocols = self.protocols.read().unwrap();
+            let dumped = candidates
+                .iter()
+                .find_map(|candidate| protocols[&protocol].get(&unmapped(candidate)));
+            if let Some(diag_msgs) = dumped {
+                return Ok(diag_msgs.clone());
+            }
+        }
+        let diag_msgs = query(diag, protocol, candidates)?;
+        let mut protocols = self.protocols.write().unwrap();
+        let sockets = protocols.entry(protocol).or_default();
+        for &diag_msg in &diag_msgs {
+            insert(sockets, diag_msg);
+        }
+        Ok(diag_msgs)
+    }
+
+    /// Dumps every `DUMP_INTERVAL`, and right away after socket events were lost
+    fn refresh(&self, mut diag: SockDiag, mut destroyed: Option<SockDestroyed>) {
+        loop {
+            let protocols: Vec<Proto> = self.protocols.read().unwrap().keys().cloned().collect();
+            for protocol in protocols {
+                if let Err(e) = self.dump(&mut diag, protocol) {
+                    eprintln!("ERROR: socket dump: {}", e);
+                }
+            }
+            let next_dump = Instant::now() + DUMP_INTERVAL;
+            loop {
+                let left = next_dump.saturating_duration_since(Instant::now());
+                if left == Duration::from_secs(0) {
+                    break;
+                }
+                let events = match &mut destroyed {
+                    Some(events) => events,
+                    None => {
+                        thread::sleep(left);
+                        continue;
+                    }
+                };
+                let mut fds = [PollFd::new(events.as_raw_fd(), PollFlags::POLLIN)];
+                match poll(&mut fds, left.as_millis() as i32) {
+                    Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                    Err(e) => panic!("{}", e),
+                }
+                match events.recv() {
+                    Ok(closed) => self.remove(closed),
+                    // some events were lost, start over
+                    Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => break,
+                    Err(e) => {
+                        eprintln!("ERROR: socket events: {}", e);
+                        destroyed = None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn dump(&self, diag: &mut SockDiag, protocol: Proto) -> Result<(), io::Error> {
+        let families: &[bool] = match protocol {
+            Proto::Icmp => &[true],
+            Proto::Icmpv6 => &[false],
+            _ => &[true, false],
+        };
+        let mut sockets = HashMap::new();
+        for &ipv4 in families {
+            for diag_msg in diag.dump(protocol, ipv4)? {
+                insert(&mut sockets, diag_msg);
+            }
+        }
+        self.protocols.write().unwrap().insert(protocol, sockets);
+        Ok(())
+    }
+
+    fn remove(&self, closed: Vec<InetDiagMsg>) {
+        let mut protocols = self.protocols.write().unwrap();
+        for diag_msg in closed {
+            let addresses = unmapped(&(diag_msg.local_address(), diag_msg.remote_address()));
+            // the event doesn't say which protocol it is, but cookies are unique
+            for dumped in protocols.values_mut() {
+                if let Some(sockets) = dumped.get_mut(&addresses) {
+                    sockets.retain(|socket| socket.cookie() != diag_msg.cookie());
+                    if sockets.is_empty() {
+                        dumped.remove(&addresses);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn insert(sockets: &mut Sockets, diag_msg: InetDiagMsg) {
+    let addresses = (diag_msg.local_address(), diag_msg.remote_address());
+    let sockets = sockets.entry(unmapped(&addresses)).or_default();
+    // found by a query after the dump
+    if sockets
+        .iter()
+        .all(|socket| socket.cookie() != diag_msg.cookie())
+    {
+        sockets.push(diag_msg);
+    }
+}
+
+/// IPv6 sockets connected to IPv4 addresses are found by their IPv4 packets
+fn unmapped(&(local_address, remote_address): &Candidate) -> Candidate {
+    fn unmap(address: SocketAddr) -> SocketAddr {
+        match address.ip() {
+            IpAddr::V6(ip) => match ip.segments() {
+                [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                    let ip = Ipv4Addr::from((hi as u32) << 16 | lo as u32);
+                    SocketAddr::new(ip.into(), address.port())
+                }
+                _ => address,
+            },
+            IpAddr::V4(_) => address,
+        }
+    }
+    match (unmap(local_address), unmap(remote_address)) {
+        (local, remote) if local.is_ipv4() == remote.is_ipv4() => (local, remote),
+        // `::ffff:0.0.0.0` is not a thing, a mapped address is connected to a mapped one
+        _ => (local_address, remote_address),
+    }
+}
+
+/// Compares both modes on the sockets of this host, `gleipnird --bench-sock-diag`
+///
+/// Connected sockets are looked up by their addresses, listeners and unconnected sockets
+/// as if a new peer sent a packet, and sockets created after the dump as new flows are.
+pub fn bench() -> Result<(), io::Error> {
+    let mut diag = SockDiag::new()?;
+    for &protocol in &[Proto::Tcp, Proto::Udp] {
+        let mut sockets = diag.dump(protocol, true)?;
+        sockets.extend(diag.dump(protocol, false)?);
+        let (listening, connected): (Vec<_>, Vec<_>) = sockets
+            .iter()
+            .partition(|s| s.remote_address().ip().is_unspecified());
+        let connected: Vec<Vec<Candidate>> = connected
+            .iter()
+            .take(BENCH_SAMPLES)
+            .map(|s| vec![(s.local_address(), s.remote_address())])
+            .collect();
+        let listening: Vec<Vec<Candidate>> = listening
+            .iter()
+            .take(BENCH_SAMPLES)
+            .map(|s| match protocol {
+                // the SYN is found as a request socket, then its listener is looked up
+                Proto::Tcp => listeners(s.local_address()).to_vec(),
+                _ => candidates(protocol, s.local_address(), new_peer(s.local_address())),
+            })
+            .collect();
+
+        let table = SocketTable::new();
+        let start = Instant::now();
+        table.dump(&mut diag, protocol)?;
+        let dumped = start.elapsed();
+        // kept open until they are looked up
+        let (_fresh_sockets, fresh) = fresh_flows(protocol)?;
+
+        println!(
+            "{}: {} sockets, dumped in {:?}",
+            protocol,
+            sockets.len(),
+            dumped
+        );
+        for (kind, samples) in &[
+            ("connected", connected),
+            ("listening", listening),
+            ("fresh", fresh),
+        ] {
+            if samples.is_empty() {
+                println!("  {}: no sockets", kind);
+                continue;
+            }
+            let start = Instant::now();
+            for sample in samples {
+                let _ = query(&mut diag, protocol, sample);
+            }
+            let queried = start.elapsed();
+            // fresh ones are not in the table until found
+            let start = Instant::now();
+            for sample in samples {
+                let _ = table.find(&mut diag, protocol, sample);
+            }
+            let found = start.elapsed();
+
+            let n = samples.len() as u32;
+            println!(
+                "  {}: {} looked up\n    query: {:?} ({:?} each)\n    table: {:?} ({:?} each)",
+                kind,
+                n,
+                queried,
+                queried / n,
+                found,
+                found / n,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// A peer the sockets of this host never talked to, from the documentation ranges
+fn new_peer(local: SocketAddr) -> SocketAddr {
+    let ip: IpAddr = if local.is_ipv4() {
+        Ipv4Addr::new(192, 0, 2, 1).into()
+    } else {
+        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into()
+    };
+    SocketAddr::new(ip, 9)
+}
+
+/// Connected loopback sockets, and their candidates
+fn fresh_flows(protocol: Proto) -> Result<(Vec<Box<dyn AsRawFd>>, Vec<Vec<Candidate>>), io::Error> {
+    let mut sockets: Vec<Box<dyn AsRawFd>> = Vec::new();
+    let mut fresh = Vec::new();
+    match protocol {
+        Proto::Tcp => {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            for _ in 0..BENCH_FRESH {
+                let stream = TcpStream::connect(listener.local_addr()?)?;
+                let (accepted, _) = listener.accept()?;
+                fresh.push(candidates(
+                    protocol,
+                    stream.local_addr()?,
+                    stream.peer_addr()?,
+                ));
+                sockets.push(Box::new(stream));
+                sockets.push(Box::new(accepted));
+            }
+            sockets.push(Box::new(listener));
+        }
+        _ => {
+            for _ in 0..BENCH_FRESH {
+                let socket = UdpSocket::bind("127.0.0.1:0")?;
+                socket.connect("127.0.0.1:9")?;
+                fresh.push(candidates(
+                    protocol,
+                    socket.local_addr()?,
+                    socket.peer_addr()?,
+                ));
+                sockets.push(Box::new(socket));
+            }
+        }
+    }
+    Ok((sockets, fresh))
+}